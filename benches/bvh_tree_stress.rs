@@ -1,12 +1,77 @@
+use cgmath::InnerSpace;
 use criterion::{criterion_group, criterion_main, Criterion};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use ray_tracing_rust::{
-    core::render::{render, RenderTarget},
+    core::bvh::{BvhSettings, SplitMethod},
+    core::mesh::Mesh,
     core::scene::Scene,
+    core::traits::Hittable,
     materials::Dielectric,
     objects::Sphere,
+    utils::aabb::Bounded,
+    utils::ray::Ray,
+    utils::sample::{SampleIndex, SamplerKind},
     utils::types::{Float, Vec3},
 };
 
+/// Rays from a sphere around the mesh towards random points inside of it
+fn rays_through(mesh: &Mesh, count: usize) -> Vec<Ray> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let bounds = mesh.bounds();
+    let center = bounds.centroid();
+    let radius = bounds.dimensions().magnitude();
+
+    (0..count)
+        .map(|_| {
+            let origin = loop {
+                let offset =
+                    Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 2.0 - Vec3::new(1.0, 1.0, 1.0);
+                if offset.magnitude2() <= 1.0 {
+                    break center + offset * radius;
+                }
+            };
+            let target = Vec3::new(
+                rng.gen_range(bounds.min.x..=bounds.max.x),
+                rng.gen_range(bounds.min.y..=bounds.max.y),
+                rng.gen_range(bounds.min.z..=bounds.max.z),
+            );
+            Ray::new(origin, target - origin)
+        })
+        .collect()
+}
+
+fn traversal_benchmark(c: &mut Criterion, path: &str) {
+    let mut scene = Scene::default();
+    let material = scene.add_material(Box::new(Dielectric::new(1.5)));
+    let mut mesh = Mesh::from_file(path, material);
+    let rays = rays_through(&mesh, 1000);
+
+    let mut sampler = SamplerKind::Independent.sampler(SampleIndex {
+        seed: 0,
+        pixel: 0,
+        sample: 0,
+        pass_sample: 0,
+        pass_samples: 1,
+    });
+
+    let mut group = c.benchmark_group(format!("traverse {}", path));
+    for split in [SplitMethod::Median, SplitMethod::Sah] {
+        mesh.build_bvh_with(&BvhSettings {
+            split,
+            ..BvhSettings::default()
+        });
+
+        group.bench_function(format!("{:?}", split), |b| {
+            b.iter(|| {
+                rays.iter()
+                    .filter_map(|ray| mesh.hit(ray, 0.001, Float::INFINITY, &mut sampler))
+                    .count()
+            })
+        });
+    }
+    group.finish();
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     let mut scene = Scene::default();
     scene.camera.lookfrom.y = -20.0;
@@ -28,6 +93,10 @@ fn criterion_benchmark(c: &mut Criterion) {
     }
 
     c.bench_function("render", |b| b.iter(|| scene.build_bvh()));
+
+    // Median splits overlap badly on these
+    traversal_benchmark(c, "assets/house.obj");
+    traversal_benchmark(c, "assets/cool_cube.obj");
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -1,5 +1,6 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 use ray_tracing_rust::{
+    core::mesh::Mesh,
     core::scene::Scene,
     materials::Dielectric,
     objects::Sphere,
@@ -25,6 +26,9 @@ fn criterion_benchmark(c: &mut Criterion) {
     }
 
     c.bench_function("render", |b| b.iter(|| scene.build_bvh()));
+
+    let mut mesh = Mesh::from_file("assets/cool_cube.obj", default_material);
+    c.bench_function("build cool_cube.obj", |b| b.iter(|| mesh.build_bvh()));
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -1,3 +1,5 @@
+use std::sync::OnceLock;
+
 use cgmath::InnerSpace;
 use serde::{Deserialize, Serialize};
 
@@ -7,11 +9,12 @@ use crate::{
         color::Color,
         math::to_spherical_coords,
         ray::Ray,
-        types::{Float, PI},
+        sample::Sampler,
+        types::{Float, Vec3, PI},
     },
 };
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct UniformBackground {
     pub color: Color,
 }
@@ -27,9 +30,13 @@ impl Background for UniformBackground {
     fn sample(&self, _: &Ray) -> Color {
         self.color
     }
+
+    fn box_clone(&self) -> Box<dyn Background> {
+        Box::new(self.clone())
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct GradientBackground {
     pub top: Color,
     pub bottom: Color,
@@ -46,13 +53,38 @@ impl Background for GradientBackground {
     fn sample(&self, ray: &Ray) -> Color {
         ray.vertical_grad(self.top, self.bottom)
     }
+
+    fn box_clone(&self) -> Box<dyn Background> {
+        Box::new(self.clone())
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SkyMap {
     image: Vec<Color>,
     width: usize,
     height: usize,
+    /// Built the first time the sky is sampled as a light
+    #[serde(skip)]
+    distribution: OnceLock<PixelDistribution>,
+}
+
+/// Running sum over all pixels of their luminance times their solid angle, used
+/// to pick pixels proportional to the light they contribute
+#[derive(Clone)]
+struct PixelDistribution {
+    cdf: Vec<Float>,
+}
+
+impl PixelDistribution {
+    fn total(&self) -> Float {
+        self.cdf.last().copied().unwrap_or(0.0)
+    }
+
+    fn probability(&self, index: usize) -> Float {
+        let previous = if index > 0 { self.cdf[index - 1] } else { 0.0 };
+        (self.cdf[index] - previous) / self.total()
+    }
 }
 
 impl SkyMap {
@@ -63,6 +95,7 @@ impl SkyMap {
                 image: vec![Color::default(); resolution.width() * resolution.height()],
                 width: resolution.width(),
                 height: resolution.height(),
+                distribution: OnceLock::new(),
             },
             |skymap: &mut Self, position, (r, g, b, _): (f32, f32, f32, f32)| {
                 skymap.image[position.x() + position.y() * skymap.width] =
@@ -78,16 +111,107 @@ impl SkyMap {
         );
         image.layer_data.channel_data.pixels
     }
-}
 
-#[typetag::serde]
-impl Background for SkyMap {
-    fn sample(&self, ray: &Ray) -> Color {
-        let spherical_coords = to_spherical_coords(ray.direction.normalize());
+    /// Size of a pixel in spherical coordinates, polar and azimuthal angle
+    fn pixel_angles(&self) -> (Float, Float) {
+        (PI / self.height as Float, 2.0 * PI / self.width as Float)
+    }
+
+    /// Polar angle of the center of a pixel row
+    fn row_theta(&self, y: usize) -> Float {
+        ((self.height - 1 - y) as Float + 0.5) * self.pixel_angles().0
+    }
+
+    fn pixel_index(&self, direction: Vec3) -> (usize, Float) {
+        let spherical_coords = to_spherical_coords(direction.normalize());
         let u = spherical_coords.x / PI;
         let v = spherical_coords.y / (2.0 * PI);
         let x = (v * self.width as Float) as usize % self.width;
         let y = self.height - 1 - (u * self.height as Float) as usize % self.height;
-        self.image[x + y * self.width]
+        (x + y * self.width, spherical_coords.x)
+    }
+
+    fn distribution(&self) -> &PixelDistribution {
+        self.distribution.get_or_init(|| {
+            let mut sum = 0.0;
+            let cdf = self
+                .image
+                .iter()
+                .enumerate()
+                .map(|(index, color)| {
+                    // Rows near the poles cover a smaller solid angle
+                    let theta = self.row_theta(index / self.width);
+                    sum += color.luminance().max(0.0) * theta.sin();
+                    sum
+                })
+                .collect();
+
+            PixelDistribution { cdf }
+        })
+    }
+}
+
+#[typetag::serde]
+impl Background for SkyMap {
+    fn sample(&self, ray: &Ray) -> Color {
+        self.image[self.pixel_index(ray.direction).0]
+    }
+
+    fn box_clone(&self) -> Box<dyn Background> {
+        Box::new(self.clone())
+    }
+
+    fn is_light(&self) -> bool {
+        true
+    }
+
+    fn sample_direction(&self, sampler: &mut dyn Sampler) -> Option<(Vec3, Float)> {
+        let distribution = self.distribution();
+        if distribution.total() <= 0.0 {
+            return None;
+        }
+
+        // Pick a pixel, then a uniform position in its spherical coordinates
+        let target = sampler.next_1d() * distribution.total();
+        let index = distribution
+            .cdf
+            .partition_point(|&sum| sum <= target)
+            .min(self.image.len() - 1);
+
+        let (x, y) = (index % self.width, index / self.width);
+        let (theta_size, phi_size) = self.pixel_angles();
+        let (u, v) = sampler.next_2d();
+        let theta = ((self.height - 1 - y) as Float + u) * theta_size;
+        let phi = (x as Float + v) * phi_size;
+
+        // Inverse of `to_spherical_coords`
+        let sin_theta = theta.sin();
+        if sin_theta <= 0.0 {
+            return None;
+        }
+        let direction = Vec3::new(
+            sin_theta * (phi - PI).cos(),
+            -theta.cos(),
+            -sin_theta * (phi - PI).sin(),
+        );
+
+        let pdf = distribution.probability(index) / (theta_size * phi_size * sin_theta);
+        Some((direction, pdf))
+    }
+
+    fn pdf(&self, direction: Vec3) -> Float {
+        let distribution = self.distribution();
+        if distribution.total() <= 0.0 {
+            return 0.0;
+        }
+
+        let (index, theta) = self.pixel_index(direction);
+        let (theta_size, phi_size) = self.pixel_angles();
+        let sin_theta = theta.sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        distribution.probability(index) / (theta_size * phi_size * sin_theta)
     }
 }
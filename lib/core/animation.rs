@@ -0,0 +1,104 @@
+use std::ops::{Add, Mul, Sub};
+
+use cgmath::{InnerSpace, Quaternion};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{transform::slerp, types::*};
+
+/// Pose of the camera at a frame of an animation
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CameraKeyframe {
+    pub frame: Float,
+    pub lookfrom: Vec3,
+    pub lookat: Vec3,
+    pub vertical_fov: Float,
+}
+
+/// Curves through the keyframes of a camera track
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Interpolation {
+    /// Straight lines between the keyframes
+    Linear,
+    /// Smooth curve through the keyframes, each segment is shaped by the
+    /// keyframes on either side of it
+    CatmullRom,
+}
+
+/// Keyframed camera animation for rendering image sequences
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraTrack {
+    /// Sorted by frame, the first and last poses are held outside of them
+    pub keyframes: Vec<CameraKeyframe>,
+    pub interpolation: Interpolation,
+    /// Turn the view direction at a constant rate between keyframes instead of
+    /// following the interpolated `lookat`
+    #[serde(default)]
+    pub slerp_orientation: bool,
+}
+
+impl CameraTrack {
+    /// Interpolated pose at `frame`, None if there are no keyframes
+    pub fn pose(&self, frame: Float) -> Option<CameraKeyframe> {
+        let keyframes = &self.keyframes;
+        let next = keyframes.partition_point(|keyframe| keyframe.frame <= frame);
+        let (index, amount) = match (next.checked_sub(1), keyframes.get(next)) {
+            (Some(previous), Some(next)) => {
+                let start = keyframes[previous].frame;
+                (previous, (frame - start) / (next.frame - start))
+            }
+            (Some(last), None) => return Some(keyframes[last]),
+            (None, Some(first)) => return Some(*first),
+            (None, None) => return None,
+        };
+
+        // The keyframes around the segment, the ends of the track are repeated
+        let key = |offset: isize| {
+            let index = (index as isize + offset).clamp(0, keyframes.len() as isize - 1);
+            &keyframes[index as usize]
+        };
+        let around = [key(-1), key(0), key(1), key(2)];
+
+        let lookfrom = self.blend(around.map(|key| key.lookfrom), amount);
+        let vertical_fov = self.blend(around.map(|key| key.vertical_fov), amount);
+        let lookat = if self.slerp_orientation {
+            let view = |key: &CameraKeyframe| key.lookat - key.lookfrom;
+            let (start, end) = (view(key(0)), view(key(1)));
+            let distance = start.magnitude() + (end.magnitude() - start.magnitude()) * amount;
+
+            let turn = Quaternion::from_arc(start.normalize(), end.normalize(), None);
+            let identity = Quaternion::new(1.0, 0.0, 0.0, 0.0);
+            lookfrom + slerp(identity, turn, amount) * start.normalize() * distance
+        } else {
+            self.blend(around.map(|key| key.lookat), amount)
+        };
+
+        Some(CameraKeyframe {
+            frame,
+            lookfrom,
+            lookat,
+            vertical_fov,
+        })
+    }
+
+    /// Value between `values[1]` and `values[2]`, the outer values shape the
+    /// curve
+    fn blend<T>(&self, values: [T; 4], t: Float) -> T
+    where
+        T: Copy + Add<Output = T> + Sub<Output = T> + Mul<Float, Output = T>,
+    {
+        let [p0, p1, p2, p3] = values;
+        match self.interpolation {
+            Interpolation::Linear => p1 + (p2 - p1) * t,
+            // Uniform Catmull-Rom spline
+            Interpolation::CatmullRom => {
+                let t2 = t * t;
+                let t3 = t2 * t;
+                (p1 * 2.0
+                    + (p2 - p0) * t
+                    + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * t2
+                    + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * t3)
+                    * 0.5
+            }
+        }
+    }
+}
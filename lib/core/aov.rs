@@ -0,0 +1,190 @@
+use cgmath::InnerSpace;
+
+use crate::utils::{color::Color, types::*};
+
+/// Arbitrary output variables, extra buffers rendered next to the beauty
+/// image for compositing and denoising
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aov {
+    /// Reflectance of the first surface hit
+    Albedo,
+    /// Shading normal of the first surface hit, facing the camera
+    Normal,
+    /// Distance from the camera to the first surface hit
+    Depth,
+    /// World space position of the first surface hit
+    Position,
+    ObjectId,
+    MaterialId,
+    /// Light scattered once by a diffuse surface
+    DirectDiffuse,
+    /// Light first scattered by a diffuse surface, then bounced around more
+    IndirectDiffuse,
+    /// Light scattered once by a specular surface
+    DirectSpecular,
+    /// Light first scattered by a specular surface, then bounced around more
+    IndirectSpecular,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 10] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::ObjectId,
+        Aov::MaterialId,
+        Aov::DirectDiffuse,
+        Aov::IndirectDiffuse,
+        Aov::DirectSpecular,
+        Aov::IndirectSpecular,
+    ];
+
+    /// Layer name used in EXR files
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::DirectDiffuse => "direct_diffuse",
+            Aov::IndirectDiffuse => "indirect_diffuse",
+            Aov::DirectSpecular => "direct_specular",
+            Aov::IndirectSpecular => "indirect_specular",
+        }
+    }
+
+    /// Channel names of the layer, the values of `AovSamples::value` are in
+    /// the same order
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::ObjectId | Aov::MaterialId => &["id"],
+            _ => &["R", "G", "B"],
+        }
+    }
+}
+
+/// Sums of the AOVs over the samples taken for one pixel
+#[derive(Debug, Clone, Copy)]
+pub struct AovSamples {
+    pub albedo: Color,
+    pub normal: Vec3,
+    /// Summed over the samples which hit something, like `position`
+    pub depth: Float,
+    pub position: Vec3,
+    /// Object seen by the first sample which hit something, ids can't be averaged
+    pub object: Option<usize>,
+    pub material: Option<usize>,
+    pub direct_diffuse: Color,
+    pub indirect_diffuse: Color,
+    pub direct_specular: Color,
+    pub indirect_specular: Color,
+    /// Number of samples which hit something
+    pub hits: u32,
+}
+
+impl AovSamples {
+    pub const fn new() -> Self {
+        const BLACK: Color = Color::new(0.0, 0.0, 0.0);
+        Self {
+            albedo: BLACK,
+            normal: Vec3::new(0.0, 0.0, 0.0),
+            depth: 0.0,
+            position: Vec3::new(0.0, 0.0, 0.0),
+            object: None,
+            material: None,
+            direct_diffuse: BLACK,
+            indirect_diffuse: BLACK,
+            direct_specular: BLACK,
+            indirect_specular: BLACK,
+            hits: 0,
+        }
+    }
+
+    pub fn add(&mut self, other: &AovSamples) {
+        self.albedo = self.albedo + other.albedo;
+        self.normal += other.normal;
+        self.depth += other.depth;
+        self.position += other.position;
+        self.object = self.object.or(other.object);
+        self.material = self.material.or(other.material);
+        self.direct_diffuse = self.direct_diffuse + other.direct_diffuse;
+        self.indirect_diffuse = self.indirect_diffuse + other.indirect_diffuse;
+        self.direct_specular = self.direct_specular + other.direct_specular;
+        self.indirect_specular = self.indirect_specular + other.indirect_specular;
+        self.hits += other.hits;
+    }
+
+    /// Averaged channels of an AOV, given the number of samples of the pixel.
+    /// Pixels where nothing was hit are infinitely deep and have an id of -1
+    pub fn value(&self, aov: Aov, samples: u32) -> [Float; 3] {
+        let per_sample = 1.0 / samples.max(1) as Float;
+        let per_hit = 1.0 / self.hits.max(1) as Float;
+        let id = |id: Option<usize>| [id.map_or(-1.0, |id| id as Float), 0.0, 0.0];
+
+        match aov {
+            Aov::Albedo => (self.albedo * per_sample).data(),
+            Aov::Normal => (self.normal * per_sample).into(),
+            Aov::Depth if self.hits == 0 => [Float::INFINITY, 0.0, 0.0],
+            Aov::Depth => [self.depth * per_hit, 0.0, 0.0],
+            Aov::Position => (self.position * per_hit).into(),
+            Aov::ObjectId => id(self.object),
+            Aov::MaterialId => id(self.material),
+            Aov::DirectDiffuse => (self.direct_diffuse * per_sample).data(),
+            Aov::IndirectDiffuse => (self.indirect_diffuse * per_sample).data(),
+            Aov::DirectSpecular => (self.direct_specular * per_sample).data(),
+            Aov::IndirectSpecular => (self.indirect_specular * per_sample).data(),
+        }
+    }
+
+    /// Color to show an AOV with on screen, lighting is left linear to be tone
+    /// mapped like the beauty image
+    pub fn display(&self, aov: Aov, samples: u32) -> Color {
+        let [x, y, z] = self.value(aov, samples);
+        match aov {
+            Aov::Normal => {
+                let normal = Vec3::new(x, y, z);
+                let normal = if normal.magnitude2() > 0.0 {
+                    normal.normalize()
+                } else {
+                    normal
+                };
+                let color = 0.5 * (normal + Vec3::new(1.0, 1.0, 1.0));
+                Color::new(color.x, color.y, color.z)
+            }
+            Aov::Depth => {
+                let shade = 1.0 / (1.0 + x);
+                Color::new(shade, shade, shade)
+            }
+            Aov::Position => Color::new(x.rem_euclid(1.0), y.rem_euclid(1.0), z.rem_euclid(1.0)),
+            Aov::ObjectId | Aov::MaterialId if x < 0.0 => Color::new(0.0, 0.0, 0.0),
+            Aov::ObjectId | Aov::MaterialId => id_color(x as usize),
+            _ => Color::new(x, y, z),
+        }
+    }
+}
+
+impl Default for AovSamples {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Picks a distinct bright color for every id
+fn id_color(id: usize) -> Color {
+    // Golden ratio steps around the hue circle keep neighbouring ids apart
+    let hue = (id as Float * 0.618_034).fract() * 6.0;
+    let x = 1.0 - ((hue % 2.0) - 1.0).abs();
+    match hue as usize {
+        0 => Color::new(1.0, x, 0.0),
+        1 => Color::new(x, 1.0, 0.0),
+        2 => Color::new(0.0, 1.0, x),
+        3 => Color::new(0.0, x, 1.0),
+        4 => Color::new(x, 0.0, 1.0),
+        _ => Color::new(1.0, 0.0, x),
+    }
+}
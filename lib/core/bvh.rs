@@ -1,10 +1,14 @@
-use rand::{thread_rng, Rng};
+use std::ops::Range;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::core::traits::Hittable;
 use crate::utils::{
     aabb::AABB,
     ray::{HitRecord, Ray},
-    types::Float,
+    sample::Sampler,
+    types::{Float, Vec3},
 };
 
 use super::scene::MaterialHandle;
@@ -13,6 +17,9 @@ use super::scene::MaterialHandle;
 pub struct BvhTree<'s, S> {
     scene: &'s S,
     root: BvhNode,
+    /// Handles of the objects, ordered so that every leaf covers a range of
+    /// them
+    objects: Vec<u32>,
 }
 
 pub trait BoundsCollection: Sync {
@@ -22,6 +29,7 @@ pub trait BoundsCollection: Sync {
         ray: &Ray,
         tmin: Float,
         tmax: Float,
+        sampler: &mut dyn Sampler,
     ) -> Option<HitRecord<MaterialHandle>>;
     fn bounds(&self, handle: u32) -> AABB;
     fn objects(&self) -> Vec<u32>;
@@ -32,135 +40,322 @@ where
     S: BoundsCollection,
 {
     /// Build a Bvh tree from a scene
-    pub fn build(scene: &'s S) -> Self {
+    pub fn build(scene: &'s S, settings: &BvhSettings) -> Self {
+        let mut objects = scene.objects();
         Self {
             scene,
-            root: BvhNode::from_list(&mut scene.objects(), scene),
+            root: BvhNode::from_list(&mut objects, scene, settings),
+            objects,
         }
     }
+
+    pub fn into_root(self) -> (BvhNode, Vec<u32>) {
+        (self.root, self.objects)
+    }
 }
 
 impl<S> Hittable for BvhTree<'_, S>
 where
     S: BoundsCollection,
 {
-    fn hit(&self, ray: &Ray, tmin: Float, tmax: Float) -> Option<HitRecord<MaterialHandle>> {
-        self.root.hit(ray, tmin, tmax, self.scene)
+    fn hit(
+        &self,
+        ray: &Ray,
+        tmin: Float,
+        tmax: Float,
+        sampler: &mut dyn Sampler,
+    ) -> Option<HitRecord<MaterialHandle>> {
+        self.root
+            .hit(ray, tmin, tmax, &self.objects, self.scene, sampler)
+    }
+}
+
+/// How the objects of a node are divided between its children
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SplitMethod {
+    /// Halves along the axis with the largest centroid spread
+    Median,
+    /// Binned surface area heuristic, minimizes the expected cost of tracing
+    /// rays through the children
+    Sah,
+}
+
+/// Options for building Bvh trees
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BvhSettings {
+    pub split: SplitMethod,
+    /// Number of buckets the centroids are sorted into along every axis when
+    /// looking for the cheapest split
+    pub bins: usize,
+    /// Most objects a leaf can hold. Median splits make leaves as soon as
+    /// nodes are this small, the surface area heuristic only when splitting
+    /// them further doesn't pay off
+    pub leaf_size: usize,
+}
+
+impl Default for BvhSettings {
+    fn default() -> Self {
+        Self {
+            split: SplitMethod::Sah,
+            bins: 16,
+            leaf_size: 4,
+        }
     }
 }
 
 pub enum BvhNode {
     None,
-    Object(u32),
+    /// Range of the reordered object list
+    Leaf(AABB, Range<u32>),
     Split(AABB, Box<BvhNode>, Box<BvhNode>),
 }
 
+/// Object together with its bounds, computed once before building
+#[derive(Clone, Copy)]
+struct Primitive {
+    handle: u32,
+    bounds: AABB,
+    centroid: Vec3,
+}
+
+/// Cheapest plane between the bins of the surface area heuristic
+struct SahSplit {
+    /// Expected cost of tracing a ray through the children, relative to
+    /// testing it against one object
+    cost: Float,
+    axis: usize,
+    /// First bin on the right of the plane
+    bin: usize,
+}
+
 impl BvhNode {
-    pub fn from_list<S>(objects: &mut Vec<u32>, scene: &S) -> Self
+    /// Nodes with more objects than this build their children in parallel
+    const PARALLEL_THRESHOLD: usize = 1024;
+    /// Cost of testing a ray against the bounds of a node, relative to testing
+    /// it against an object
+    const TRAVERSAL_COST: Float = 0.125;
+    const INTERSECTION_COST: Float = 1.0;
+
+    /// Builds a tree over the objects, reordering them so that the objects of
+    /// every leaf are next to each other
+    pub fn from_list<S>(objects: &mut [u32], scene: &S, settings: &BvhSettings) -> Self
     where
         S: BoundsCollection,
     {
-        match objects.len() {
-            0 => BvhNode::None,
-            1 => BvhNode::Object(objects[0]),
-            _ => {
-                // Calculate bounds for the whole node
-                let bounds = objects
-                    .iter()
-                    .map(|object| scene.bounds(*object))
-                    .reduce(|a, b| AABB::surround(a, b))
-                    .unwrap_or_default();
+        let mut primitives: Vec<Primitive> = objects
+            .par_iter()
+            .map(|&handle| {
+                let bounds = scene.bounds(handle);
+                Primitive {
+                    handle,
+                    bounds,
+                    centroid: bounds.centroid(),
+                }
+            })
+            .collect();
 
-                // See which axis has the greatest variance among centroids
-                let centroids = objects
-                    .iter()
-                    .map(|object| AABB::from_point(scene.bounds(*object).centroid()))
-                    .reduce(|a, b| AABB::surround(a, b))
-                    .unwrap_or_default();
-
-                let spread = centroids.max - centroids.min;
-                let axis = if spread.x > spread.y && spread.x > spread.z {
-                    0
-                } else if spread.y > spread.x && spread.y > spread.z {
-                    1
-                } else {
-                    2
-                };
+        let root = Self::build(&mut primitives, 0, settings);
+        for (object, primitive) in objects.iter_mut().zip(&primitives) {
+            *object = primitive.handle;
+        }
 
-                // Sort objects by laying bounding boxes along an axis
-                match axis {
-                    0 => {
-                        objects.sort_by(|a, b| {
-                            scene
-                                .bounds(*a)
-                                .centroid()
-                                .x
-                                .partial_cmp(&scene.bounds(*b).centroid().x)
-                                .unwrap()
-                        });
-                    }
-                    1 => {
-                        objects.sort_by(|a, b| {
-                            scene
-                                .bounds(*a)
-                                .centroid()
-                                .y
-                                .partial_cmp(&scene.bounds(*b).centroid().y)
-                                .unwrap()
-                        });
-                    }
-                    _ => {
-                        objects.sort_by(|a, b| {
-                            scene
-                                .bounds(*a)
-                                .centroid()
-                                .z
-                                .partial_cmp(&scene.bounds(*b).centroid().z)
-                                .unwrap()
-                        });
-                    }
-                }
+        root
+    }
 
-                // Assign first half to left half and second half to right half
-                let mut left_list = Vec::new();
-                let mut right_list = Vec::new();
+    /// Node over `primitives`, which start at `start` in the whole list
+    fn build(primitives: &mut [Primitive], start: u32, settings: &BvhSettings) -> Self {
+        // Calculate bounds for the whole node
+        let bounds = match primitives
+            .iter()
+            .map(|primitive| primitive.bounds)
+            .reduce(AABB::surround)
+        {
+            Some(bounds) => bounds,
+            None => return BvhNode::None,
+        };
+
+        let centroids = primitives
+            .iter()
+            .map(|primitive| AABB::from_point(primitive.centroid))
+            .reduce(AABB::surround)
+            .unwrap_or_default();
+
+        let bins = settings.bins.max(2);
+        let split = match settings.split {
+            SplitMethod::Sah => Self::sah_split(primitives, &bounds, &centroids, bins),
+            SplitMethod::Median => None,
+        };
+
+        // Small nodes become leaves unless tracing rays through children is
+        // expected to be cheaper than testing all of their objects
+        if primitives.len() <= settings.leaf_size.max(1) {
+            let leaf_cost = Self::INTERSECTION_COST * primitives.len() as Float;
+            if split.as_ref().is_none_or(|split| split.cost >= leaf_cost) {
+                return BvhNode::Leaf(bounds, start..start + primitives.len() as u32);
+            }
+        }
 
-                for (index, element) in objects.iter().enumerate() {
-                    if index < objects.len() / 2 {
-                        left_list.push(*element);
-                    } else {
-                        right_list.push(*element);
-                    }
+        let middle = match split {
+            Some(split) => Self::partition_sah(primitives, &centroids, bins, &split),
+            None => Self::partition_median(primitives, &centroids),
+        };
+
+        // Create the node, the children of big nodes are built in parallel
+        let parallel = primitives.len() > Self::PARALLEL_THRESHOLD;
+        let (left, right) = primitives.split_at_mut(middle);
+        let right_start = start + middle as u32;
+        let (left, right) = if parallel {
+            rayon::join(
+                || BvhNode::build(left, start, settings),
+                || BvhNode::build(right, right_start, settings),
+            )
+        } else {
+            (
+                BvhNode::build(left, start, settings),
+                BvhNode::build(right, right_start, settings),
+            )
+        };
+
+        BvhNode::Split(bounds, Box::new(left), Box::new(right))
+    }
+
+    /// Moves the primitives along the axis with the greatest variance among
+    /// centroids, the first half goes to the left child
+    fn partition_median(primitives: &mut [Primitive], centroids: &AABB) -> usize {
+        let spread = centroids.dimensions();
+        let axis = if spread.x > spread.y && spread.x > spread.z {
+            0
+        } else if spread.y > spread.x && spread.y > spread.z {
+            1
+        } else {
+            2
+        };
+
+        let middle = primitives.len() / 2;
+        primitives.select_nth_unstable_by(middle, |a, b| {
+            a.centroid[axis].partial_cmp(&b.centroid[axis]).unwrap()
+        });
+
+        middle
+    }
+
+    /// Bin of a primitive along an axis, the bins evenly divide the bounds of
+    /// the centroids
+    fn sah_bin(primitive: &Primitive, centroids: &AABB, bins: usize, axis: usize) -> usize {
+        let offset =
+            (primitive.centroid[axis] - centroids.min[axis]) / centroids.dimensions()[axis];
+        ((offset * bins as Float) as usize).min(bins - 1)
+    }
+
+    /// Sorts the centroids into bins along every axis and finds the cheapest
+    /// plane between bins, None if the centroids all coincide
+    fn sah_split(
+        primitives: &[Primitive],
+        bounds: &AABB,
+        centroids: &AABB,
+        bins: usize,
+    ) -> Option<SahSplit> {
+        let extent = centroids.dimensions();
+        let surround = |a: Option<AABB>, b: Option<AABB>| match (a, b) {
+            (Some(a), Some(b)) => Some(AABB::surround(a, b)),
+            (a, b) => a.or(b),
+        };
+        let area = |bounds: Option<AABB>| bounds.map_or(0.0, |bounds| bounds.surface_area());
+
+        let mut best: Option<SahSplit> = None;
+        for axis in 0..3 {
+            if extent[axis] <= 0.0 {
+                continue;
+            }
+
+            let mut counts = vec![0; bins];
+            let mut boxes = vec![None; bins];
+            for primitive in primitives {
+                let index = Self::sah_bin(primitive, centroids, bins, axis);
+                counts[index] += 1;
+                boxes[index] = surround(boxes[index], Some(primitive.bounds));
+            }
+
+            // Area and count of everything right of each plane
+            let mut right = vec![(0.0, 0); bins];
+            let (mut right_bounds, mut right_count) = (None, 0);
+            for index in (1..bins).rev() {
+                right_bounds = surround(right_bounds, boxes[index]);
+                right_count += counts[index];
+                right[index] = (area(right_bounds), right_count);
+            }
+
+            let (mut left_bounds, mut left_count) = (None, 0);
+            for bin in 1..bins {
+                left_bounds = surround(left_bounds, boxes[bin - 1]);
+                left_count += counts[bin - 1];
+
+                let (right_area, right_count) = right[bin];
+                if left_count == 0 || right_count == 0 {
+                    continue;
                 }
 
-                // Create the node
-                BvhNode::Split(
-                    bounds,
-                    Box::new(BvhNode::from_list(&mut left_list, scene)),
-                    Box::new(BvhNode::from_list(&mut right_list, scene)),
-                )
+                // Rays hit the children with a chance proportional to their
+                // surface area
+                let cost = Self::TRAVERSAL_COST
+                    + Self::INTERSECTION_COST
+                        * (area(left_bounds) * left_count as Float
+                            + right_area * right_count as Float)
+                        / bounds.surface_area().max(Float::MIN_POSITIVE);
+                if best.as_ref().is_none_or(|best| cost < best.cost) {
+                    best = Some(SahSplit { cost, axis, bin });
+                }
             }
         }
+
+        best
     }
 
+    /// Moves the primitives left of the split to the front, returns how many
+    /// there are
+    fn partition_sah(
+        primitives: &mut [Primitive],
+        centroids: &AABB,
+        bins: usize,
+        split: &SahSplit,
+    ) -> usize {
+        let mut middle = 0;
+        for index in 0..primitives.len() {
+            if Self::sah_bin(&primitives[index], centroids, bins, split.axis) < split.bin {
+                primitives.swap(index, middle);
+                middle += 1;
+            }
+        }
+
+        middle
+    }
+
+    /// `objects` is the object list the tree was built from
     pub fn hit<S>(
         &self,
         ray: &Ray,
         tmin: Float,
         tmax: Float,
+        objects: &[u32],
         scene: &S,
+        sampler: &mut dyn Sampler,
     ) -> Option<HitRecord<MaterialHandle>>
     where
         S: BoundsCollection,
     {
         match self {
-            BvhNode::Object(handle) => {
-                return scene.hit(*handle, ray, tmin, tmax);
+            BvhNode::Leaf(bounds, range) if bounds.hit(ray, tmin, tmax) => {
+                return objects[range.start as usize..range.end as usize]
+                    .iter()
+                    .map(|handle| scene.hit(*handle, ray, tmin, tmax, sampler))
+                    .reduce(merge_optionals)
+                    .flatten();
             }
             BvhNode::Split(bounds, left, right) => {
                 if bounds.hit(ray, tmin, tmax) {
-                    let hit_left = left.hit(ray, tmin, tmax, scene);
-                    let hit_right = right.hit(ray, tmin, tmax, scene);
+                    let hit_left = left.hit(ray, tmin, tmax, objects, scene, sampler);
+                    let hit_right = right.hit(ray, tmin, tmax, objects, scene, sampler);
 
                     return merge_optionals(hit_left, hit_right);
                 }
@@ -198,96 +393,261 @@ where
     }
 }
 
-// Compressed Bvh tree representation
-// pub struct LinearBvhTree<'a> {
-//     scene: &'a Scene,
-//     nodes: Vec<LinearBvhNode>,
-// }
-
-// enum LinearBvhNode {
-//     Object(AABB, ObjectHandle),
-//     Split(AABB, u32, u32),
-// }
-
-// impl<'a> LinearBvhTree<'a> {
-//     pub fn flatten(bvh: BvhTree<'a>) -> Self {
-//         let mut nodes = Vec::new();
-
-//         fn flatten_internal(bvh_node: BvhNode, start: u32,) -> u32 {
-
-//         }
-
-//         Self { scene: bvh.scene, nodes, }
-//     }
-// }
-
-// impl Hittable for LinearBvhTree<'_> {
-//     fn hit(&self, ray: &Ray, tmin: Float, tmax: Float) -> Option<HitRecord<MaterialHandle>> {
-//         if self.nodes.is_empty() {
-//             return None;
-//         }
-
-//         let mut node = &self.nodes[0];
-//         loop {
-//             match node {
-//                 LinearBvhNode::Object(bounds, handle) => {
-//                     if bounds.hit(ray, tmin, tmax) {
-//                         return self.scene.object(*handle).hit(ray, tmin, tmax);
-//                     }
-//                 }
-//                 LinearBvhNode::Split(bounds, left, right) => {
-//                     if bounds.hit(ray, tmin, tmax) {
-//                         let left = &self.nodes[*left as usize];
-//                         let right = &self.nodes[*right as usize];
-
-//                         return None;
-
-// //                         match (left, right) => {
-
-// //                         }
-
-//                         // let hit_left = left.hit(ray, tmin, tmax, scene);
-//                         // let hit_right = right.hit(ray, tmin, tmax, scene);
-
-//                         // match (hit_left, hit_right) {
-//                         //     (Some(record_left), Some(record_right)) => {
-//                         //         if record_left.t < record_right.t {
-//                         //             return Some(record_left);
-//                         //         } else {
-//                         //             return Some(record_right);
-//                         //         }
-//                         //     }
-//                         //     (Some(record_left), None) => {
-//                         //         return Some(record_left);
-//                         //     }
-//                         //     (None, Some(record_right)) => {
-//                         //         return Some(record_right);
-//                         //     }
-//                         //     _ => {
-//                         //         return None;
-//                         //     }
-//                         // }
-//                     }
-//                 }
-//                 _ => {}
-//             }
-//         }
-//     }
-// }
-
-// // impl LinearBvhNode {
-// //     fn hit(&self, ray: &Ray, tmin: Float, tmax: Float, scene: &Scene) -> Option<HitRecord<MaterialHandle>> {
-// //         match self {
-// //             LinearBvhNode::Object(bounds, handle) => {
-// //                 if bounds.hit(ray, tmin, tmax) {
-// //                     return scene.object(*handle).hit(ray, tmin, tmax);
-// //                 }
-
-// //                 None
-// //             },
-// //             LinearBvhNode::Split(bounds, left, right) => {
-
-// //             },
-// //         }
-// //     }
-// // }
+/// Bvh tree flattened into an array, which is faster to traverse
+pub struct LinearBvhTree<'s, S> {
+    scene: &'s S,
+    bvh: LinearBvh,
+}
+
+impl<'s, S> LinearBvhTree<'s, S>
+where
+    S: BoundsCollection,
+{
+    pub fn flatten(bvh: BvhTree<'s, S>) -> Self {
+        Self {
+            scene: bvh.scene,
+            bvh: LinearBvh::flatten(bvh.root, bvh.objects),
+        }
+    }
+
+    pub fn into_bvh(self) -> LinearBvh {
+        self.bvh
+    }
+}
+
+impl<S> Hittable for LinearBvhTree<'_, S>
+where
+    S: BoundsCollection,
+{
+    fn hit(
+        &self,
+        ray: &Ray,
+        tmin: Float,
+        tmax: Float,
+        sampler: &mut dyn Sampler,
+    ) -> Option<HitRecord<MaterialHandle>> {
+        self.bvh.hit(ray, tmin, tmax, self.scene, sampler)
+    }
+}
+
+/// Nodes of a Bvh tree in depth first order, the first child of a split
+/// directly follows it
+#[derive(Clone, Default)]
+pub struct LinearBvh {
+    nodes: Vec<LinearBvhNode>,
+    /// Handles of the objects, ordered so that every leaf covers a range of
+    /// them
+    objects: Vec<u32>,
+}
+
+#[derive(Clone)]
+pub enum LinearBvhNode {
+    Leaf {
+        bounds: AABB,
+        start: u32,
+        end: u32,
+    },
+    /// The children are ordered along `axis`, the second one is at `second`
+    Split {
+        bounds: AABB,
+        second: u32,
+        axis: u8,
+    },
+}
+
+impl LinearBvh {
+    /// `objects` is the object list the tree was built from
+    pub fn flatten(root: BvhNode, objects: Vec<u32>) -> Self {
+        let mut nodes = Vec::new();
+        Self::flatten_node(root, &mut nodes);
+
+        Self { nodes, objects }
+    }
+
+    fn flatten_node(node: BvhNode, nodes: &mut Vec<LinearBvhNode>) {
+        match node {
+            BvhNode::Leaf(bounds, range) => nodes.push(LinearBvhNode::Leaf {
+                bounds,
+                start: range.start,
+                end: range.end,
+            }),
+            BvhNode::Split(bounds, left, right) => {
+                let centroid = |node: &BvhNode| match node {
+                    BvhNode::Leaf(bounds, _) | BvhNode::Split(bounds, _, _) => bounds.centroid(),
+                    BvhNode::None => bounds.centroid(),
+                };
+
+                // Order the children along the axis separating them the most
+                let offset = centroid(&right) - centroid(&left);
+                let axis = (0..3)
+                    .max_by(|a, b| offset[*a].abs().partial_cmp(&offset[*b].abs()).unwrap())
+                    .unwrap();
+                let (first, second) = if offset[axis] < 0.0 {
+                    (right, left)
+                } else {
+                    (left, right)
+                };
+
+                let index = nodes.len();
+                nodes.push(LinearBvhNode::Split {
+                    bounds,
+                    second: 0,
+                    axis: axis as u8,
+                });
+                Self::flatten_node(*first, nodes);
+
+                let second_index = nodes.len() as u32;
+                if let LinearBvhNode::Split { second, .. } = &mut nodes[index] {
+                    *second = second_index;
+                }
+                Self::flatten_node(*second, nodes);
+            }
+            BvhNode::None => {}
+        }
+    }
+
+    /// Recomputes the bounds of every node after the objects moved, keeping
+    /// the structure of the tree. Much faster than rebuilding it, but the
+    /// tree gets worse the further objects move from where it was built
+    pub fn refit<S>(&mut self, scene: &S)
+    where
+        S: BoundsCollection,
+    {
+        // Children come after their parents, so going backwards refits them
+        // first
+        for index in (0..self.nodes.len()).rev() {
+            let refit = match &self.nodes[index] {
+                LinearBvhNode::Leaf { start, end, .. } => self.objects
+                    [*start as usize..*end as usize]
+                    .iter()
+                    .map(|handle| scene.bounds(*handle))
+                    .reduce(AABB::surround),
+                LinearBvhNode::Split { second, .. } => Some(AABB::surround(
+                    self.nodes[index + 1].bounds(),
+                    self.nodes[*second as usize].bounds(),
+                )),
+            };
+
+            if let Some(refit) = refit {
+                match &mut self.nodes[index] {
+                    LinearBvhNode::Leaf { bounds, .. } | LinearBvhNode::Split { bounds, .. } => {
+                        *bounds = refit
+                    }
+                }
+            }
+        }
+    }
+
+    /// Visits the child nearer to the ray origin first, so that farther
+    /// nodes can be skipped once something closer is hit
+    pub fn hit<S>(
+        &self,
+        ray: &Ray,
+        tmin: Float,
+        tmax: Float,
+        scene: &S,
+        sampler: &mut dyn Sampler,
+    ) -> Option<HitRecord<MaterialHandle>>
+    where
+        S: BoundsCollection,
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let backwards = [
+            ray.direction.x < 0.0,
+            ray.direction.y < 0.0,
+            ray.direction.z < 0.0,
+        ];
+
+        let mut result = None;
+        let mut closest_so_far = tmax;
+        let mut stack = NodeStack::new();
+        let mut index = 0;
+
+        loop {
+            match &self.nodes[index] {
+                LinearBvhNode::Leaf { bounds, start, end } => {
+                    if bounds.hit(ray, tmin, closest_so_far) {
+                        for handle in &self.objects[*start as usize..*end as usize] {
+                            if let Some(hit) =
+                                scene.hit(*handle, ray, tmin, closest_so_far, sampler)
+                            {
+                                if hit.t < closest_so_far {
+                                    closest_so_far = hit.t;
+                                    result = Some(hit);
+                                }
+                            }
+                        }
+                    }
+                }
+                LinearBvhNode::Split {
+                    bounds,
+                    second,
+                    axis,
+                } => {
+                    if bounds.hit(ray, tmin, closest_so_far) {
+                        let (near, far) = if backwards[*axis as usize] {
+                            (*second as usize, index + 1)
+                        } else {
+                            (index + 1, *second as usize)
+                        };
+
+                        stack.push(far);
+                        index = near;
+                        continue;
+                    }
+                }
+            }
+
+            match stack.pop() {
+                Some(next) => index = next,
+                None => return result,
+            }
+        }
+    }
+}
+
+impl LinearBvhNode {
+    fn bounds(&self) -> AABB {
+        match self {
+            LinearBvhNode::Leaf { bounds, .. } | LinearBvhNode::Split { bounds, .. } => *bounds,
+        }
+    }
+}
+
+/// Nodes waiting to be visited, only allocates for unusually deep trees
+struct NodeStack {
+    nodes: [usize; 64],
+    len: usize,
+    overflow: Vec<usize>,
+}
+
+impl NodeStack {
+    fn new() -> Self {
+        Self {
+            nodes: [0; 64],
+            len: 0,
+            overflow: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, node: usize) {
+        if self.len < self.nodes.len() {
+            self.nodes[self.len] = node;
+            self.len += 1;
+        } else {
+            self.overflow.push(node);
+        }
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if let Some(node) = self.overflow.pop() {
+            return Some(node);
+        }
+
+        self.len = self.len.checked_sub(1)?;
+        Some(self.nodes[self.len])
+    }
+}
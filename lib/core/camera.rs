@@ -1,40 +1,305 @@
 use crate::utils::{
     math::degrees_to_radians,
     ray::Ray,
-    types::{Float, Vec3},
+    sample::{sample_regular_polygon, sample_unit_disk, Sampler},
+    types::{Float, Vec3, PI},
 };
 use cgmath::InnerSpace;
 use derive_new::new;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, new)]
+use super::animation::CameraTrack;
+
+/// How points of the image map to rays leaving the camera
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Projection {
+    /// Pinhole or thin lens camera with the field of view of the camera
+    #[default]
+    Perspective,
+    /// Parallel rays, `height` is the size of the view in scene units
+    Orthographic { height: Float },
+    /// Full 360° by 180° panorama, longitude along x and latitude along y
+    Equirectangular,
+    /// Equi-angular fisheye whose image circle fits the height of the image,
+    /// the angle from the center grows linearly up to `fov / 2` degrees
+    Fisheye { fov: Float },
+    /// Panorama wrapped around the vertical axis, `fov` degrees wide and with
+    /// the vertical field of view of the camera
+    Cylindrical { fov: Float },
+}
+
+/// How the views of the two eyes share the image
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum StereoLayout {
+    /// Left eye on the left half
+    SideBySide,
+    /// Left eye on the top half
+    TopBottom,
+}
+
+/// Renders the views of two eyes into one image
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Stereo {
+    /// Distance between the eyes
+    pub interaxial: Float,
+    /// Distance to the plane which appears at the depth of the screen, the
+    /// focus distance of the camera if None
+    #[serde(default)]
+    pub convergence: Option<Float>,
+    pub layout: StereoLayout,
+}
+
+/// Ray origins of both eyes of a stereo camera
+#[derive(Debug)]
+pub struct StereoEyes {
+    pub layout: StereoLayout,
+    pub left: Box<RayOrigin>,
+    pub right: Box<RayOrigin>,
+}
+
+impl StereoEyes {
+    fn get_ray(&self, s: Float, t: Float, sampler: &mut dyn Sampler) -> Option<Ray> {
+        match self.layout {
+            StereoLayout::SideBySide if s < 0.5 => self.left.get_ray(2.0 * s, t, sampler),
+            StereoLayout::SideBySide => self.right.get_ray(2.0 * s - 1.0, t, sampler),
+            StereoLayout::TopBottom if t < 0.5 => self.left.get_ray(s, 2.0 * t, sampler),
+            StereoLayout::TopBottom => self.right.get_ray(s, 2.0 * t - 1.0, sampler),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct RayOrigin {
+    pub projection: Projection,
     pub origin: Vec3,
     pub horizontal: Vec3,
     pub vertical: Vec3,
     pub lower_left_corner: Vec3,
+    /// Orthonormal frame of the camera, `right` and `up` also span the lens
+    pub right: Vec3,
+    pub up: Vec3,
+    pub forward: Vec3,
+    pub aspect_ratio: Float,
+    /// Tangent of half the vertical field of view
+    pub half_height: Float,
+    pub focus_distance: Float,
+    pub lens_radius: Float,
+    /// Number of aperture blades, anything below three is a round aperture
+    pub blades: u32,
+    /// Rotation of the aperture polygon in radians
+    pub blade_rotation: Float,
+    pub shutter_open: Float,
+    pub shutter_close: Float,
+    /// Set for stereo cameras, which hand every ray to one of the eyes
+    pub eyes: Option<StereoEyes>,
 }
 
 impl RayOrigin {
-    pub fn get_ray(&self, s: Float, t: Float) -> Ray {
-        Ray::new(
-            self.origin,
-            self.lower_left_corner + self.horizontal * s - self.vertical * t - self.origin,
-        )
+    /// Ray through the point `(s, t)` of the image, measured from the top left
+    /// corner. Unless the camera is a pinhole, the ray starts at a point on the
+    /// lens drawn from `sampler`. Panoramic projections are always pinholes.
+    /// While the shutter is open for a while, the time of the ray is drawn
+    /// after the lens. Returns None for points outside the image circle of a
+    /// fisheye
+    pub fn get_ray(&self, s: Float, t: Float, sampler: &mut dyn Sampler) -> Option<Ray> {
+        if let Some(eyes) = &self.eyes {
+            return eyes.get_ray(s, t, sampler);
+        }
+
+        let mut ray = self.spatial_ray(s, t, sampler)?;
+        ray.time = if self.shutter_close > self.shutter_open {
+            self.shutter_open + (self.shutter_close - self.shutter_open) * sampler.next_1d()
+        } else {
+            self.shutter_open
+        };
+        Some(ray)
+    }
+
+    fn spatial_ray(&self, s: Float, t: Float, sampler: &mut dyn Sampler) -> Option<Ray> {
+        // Rays of a pinhole meet the focus plane at `origin + direction`
+        let (origin, direction) = match self.projection {
+            Projection::Perspective => (
+                self.origin,
+                self.lower_left_corner + self.horizontal * s - self.vertical * t - self.origin,
+            ),
+            Projection::Orthographic { .. } => (
+                self.lower_left_corner + self.horizontal * s - self.vertical * t,
+                self.forward * self.focus_distance,
+            ),
+            Projection::Equirectangular => {
+                let longitude = (s - 0.5) * 2.0 * PI;
+                let latitude = (0.5 - t) * PI;
+                let direction = self.panorama_direction(longitude, 0.0) * latitude.cos()
+                    + self.up * latitude.sin();
+                return Some(Ray::new(self.origin, direction));
+            }
+            Projection::Fisheye { fov } => {
+                let x = (2.0 * s - 1.0) * self.aspect_ratio;
+                let y = 1.0 - 2.0 * t;
+                let radius = (x * x + y * y).sqrt();
+                if radius > 1.0 {
+                    return None;
+                }
+
+                let angle = radius * degrees_to_radians(fov) / 2.0;
+                let sideways = if radius > 0.0 {
+                    (self.right * x + self.up * y) / radius
+                } else {
+                    self.right
+                };
+                let direction = self.forward * angle.cos() + sideways * angle.sin();
+                return Some(Ray::new(self.origin, direction));
+            }
+            Projection::Cylindrical { fov } => {
+                let longitude = (s - 0.5) * degrees_to_radians(fov);
+                let height = (1.0 - 2.0 * t) * self.half_height;
+                let direction = self.panorama_direction(longitude, height);
+                return Some(Ray::new(self.origin, direction));
+            }
+        };
+
+        if self.lens_radius <= 0.0 {
+            return Some(Ray::new(origin, direction));
+        }
+
+        let (x, y) = if self.blades >= 3 {
+            sample_regular_polygon(self.blades, self.blade_rotation, sampler.next_2d())
+        } else {
+            sample_unit_disk(sampler.next_2d())
+        };
+        let lens_point = origin + (self.right * x + self.up * y) * self.lens_radius;
+        Some(Ray::new(lens_point, origin + direction - lens_point))
+    }
+
+    /// Horizontal direction `longitude` radians right of forward, raised by
+    /// `height` along the up axis
+    fn panorama_direction(&self, longitude: Float, height: Float) -> Vec3 {
+        self.forward * longitude.cos() + self.right * longitude.sin() + self.up * height
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, new)]
+#[derive(Debug, Clone, Serialize, Deserialize, new)]
 pub struct Camera {
     pub lookfrom: Vec3,
     pub lookat: Vec3,
     pub vertical: Vec3,
     pub vertical_fov: Float,
     pub aspect_ratio: Float,
+    /// Radius of the lens, zero for a pinhole camera where everything is sharp
+    #[serde(default)]
+    #[new(default)]
+    pub aperture: Float,
+    /// Distance from `lookfrom` to the plane in focus
+    #[serde(default = "default_focus_distance")]
+    #[new(value = "1.0")]
+    pub focus_distance: Float,
+    /// Keep `lookat` in focus, ignoring `focus_distance`
+    #[serde(default = "default_autofocus")]
+    #[new(value = "true")]
+    pub autofocus: bool,
+    /// Number of aperture blades shaping the bokeh, zero for a round aperture
+    #[serde(default)]
+    #[new(default)]
+    pub blades: u32,
+    /// Rotation of the aperture blades in degrees
+    #[serde(default)]
+    #[new(default)]
+    pub blade_rotation: Float,
+    #[serde(default)]
+    #[new(default)]
+    pub projection: Projection,
+    /// Time the shutter opens at, objects are blurred over their motion until
+    /// it closes
+    #[serde(default)]
+    #[new(default)]
+    pub shutter_open: Float,
+    #[serde(default)]
+    #[new(default)]
+    pub shutter_close: Float,
+    /// Animation followed when rendering image sequences
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[new(default)]
+    pub track: Option<CameraTrack>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[new(default)]
+    pub stereo: Option<Stereo>,
+}
+
+fn default_focus_distance() -> Float {
+    1.0
+}
+
+fn default_autofocus() -> bool {
+    true
 }
 
 impl Camera {
+    /// The camera at a frame of its track. The shutter is moved along with the
+    /// frame, so object motion is keyframed in frames as well
+    pub fn at_frame(&self, frame: Float) -> Camera {
+        let mut camera = self.clone();
+        if let Some(pose) = self.track.as_ref().and_then(|track| track.pose(frame)) {
+            camera.lookfrom = pose.lookfrom;
+            camera.lookat = pose.lookat;
+            camera.vertical_fov = pose.vertical_fov;
+        }
+
+        camera.shutter_open += frame;
+        camera.shutter_close += frame;
+        camera
+    }
+
+    /// Distance at which the camera is focused
+    pub fn focus_distance(&self) -> Float {
+        if self.autofocus {
+            (self.lookat - self.lookfrom).magnitude()
+        } else {
+            self.focus_distance
+        }
+    }
+
     pub fn ray_origin(&self) -> RayOrigin {
+        match &self.stereo {
+            Some(stereo) => self.stereo_ray_origin(stereo),
+            None => self.mono_ray_origin(),
+        }
+    }
+
+    /// Both eyes look parallel to the camera from either side of it
+    fn stereo_ray_origin(&self, stereo: &Stereo) -> RayOrigin {
+        let aspect_ratio = match stereo.layout {
+            StereoLayout::SideBySide => self.aspect_ratio / 2.0,
+            StereoLayout::TopBottom => self.aspect_ratio * 2.0,
+        };
+        let convergence = stereo.convergence.unwrap_or_else(|| self.focus_distance());
+        let right = self.vertical.cross(self.lookfrom - self.lookat).normalize();
+
+        let eye = |offset: Float| {
+            let mut camera = self.clone();
+            camera.stereo = None;
+            camera.aspect_ratio = aspect_ratio;
+            camera.lookfrom += right * offset;
+            camera.lookat += right * offset;
+            let mut origin = camera.mono_ray_origin();
+
+            // Off-axis views, so objects at the convergence distance end up in
+            // the same place for both eyes
+            if self.projection == Projection::Perspective && convergence > 0.0 {
+                origin.lower_left_corner -= right * offset * origin.focus_distance / convergence;
+            }
+            Box::new(origin)
+        };
+
+        let mut origin = self.mono_ray_origin();
+        origin.eyes = Some(StereoEyes {
+            layout: stereo.layout,
+            left: eye(-stereo.interaxial / 2.0),
+            right: eye(stereo.interaxial / 2.0),
+        });
+        origin
+    }
+
+    fn mono_ray_origin(&self) -> RayOrigin {
         let theta = degrees_to_radians(self.vertical_fov);
         let h = Float::tan(theta / 2.0);
         let viewport_height = 2.0 * h;
@@ -44,14 +309,53 @@ impl Camera {
         let u = (self.vertical.cross(w)).normalize();
         let v = w.cross(u);
 
-        let horizontal = viewport_width * u;
-        let vertical = viewport_height * v;
+        // The viewport is moved out to the focus plane, where rays from every
+        // point of the lens meet. A pinhole is in focus at any distance
+        let focus_distance = if self.aperture > 0.0 {
+            self.focus_distance()
+        } else {
+            1.0
+        };
+        let (horizontal, vertical, lower_left_corner) = match self.projection {
+            // The view of an orthographic camera starts on the camera plane
+            Projection::Orthographic { height } => {
+                let horizontal = height * self.aspect_ratio * u;
+                let vertical = height * v;
+                (
+                    horizontal,
+                    vertical,
+                    self.lookfrom - horizontal / 2.0 + vertical / 2.0,
+                )
+            }
+            _ => {
+                let horizontal = focus_distance * viewport_width * u;
+                let vertical = focus_distance * viewport_height * v;
+                (
+                    horizontal,
+                    vertical,
+                    self.lookfrom - horizontal / 2.0 + vertical / 2.0 - focus_distance * w,
+                )
+            }
+        };
 
         RayOrigin {
+            projection: self.projection,
             origin: self.lookfrom,
             horizontal,
             vertical,
-            lower_left_corner: self.lookfrom - horizontal / 2.0 + vertical / 2.0 - w,
+            lower_left_corner,
+            right: u,
+            up: v,
+            forward: -w,
+            aspect_ratio: self.aspect_ratio,
+            half_height: h,
+            focus_distance,
+            lens_radius: self.aperture,
+            blades: self.blades,
+            blade_rotation: degrees_to_radians(self.blade_rotation),
+            shutter_open: self.shutter_open,
+            shutter_close: self.shutter_close,
+            eyes: None,
         }
     }
 }
@@ -0,0 +1,138 @@
+use cgmath::InnerSpace;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{color::Color, types::*};
+
+use super::aov::Aov;
+use super::render::RenderTarget;
+
+/// Joint bilateral filter run over the accumulated image. Neighbouring pixels
+/// are averaged unless the albedo and normal AOVs tell them apart, or their
+/// colors differ by more than their noise explains
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Denoiser {
+    /// Half the side length of the filter window in pixels
+    pub radius: usize,
+    /// Standard deviation of the spatial falloff in pixels
+    pub sigma_spatial: Float,
+    /// Color differences are measured in multiples of the noise of the pixels
+    pub sigma_color: Float,
+    pub sigma_albedo: Float,
+    pub sigma_normal: Float,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            radius: 5,
+            sigma_spatial: 3.0,
+            sigma_color: 2.0,
+            sigma_albedo: 0.1,
+            sigma_normal: 0.3,
+        }
+    }
+}
+
+/// Per pixel inputs of the filter
+struct Guide {
+    color: Color,
+    /// Variance of the mean luminance of the pixel
+    variance: Float,
+    albedo: Option<Color>,
+    normal: Option<Vec3>,
+}
+
+impl Denoiser {
+    /// Smallest standard deviation the filter uses, smaller ones would divide
+    /// zero by zero for the center pixel
+    const MIN_SIGMA: Float = 1e-4;
+
+    /// Filters the linear image of a render target. Without AOVs the filter
+    /// can only go by color and blurs more across edges. Pixels without samples,
+    /// like those outside of the render region, are left out of it
+    pub fn apply(&self, target: &RenderTarget) -> Vec<Color> {
+        let guides: Vec<Option<Guide>> = (0..target.width * target.height)
+            .map(|index| {
+                (target.pixel_samples[index] > 0).then(|| Guide {
+                    color: target.noisy(index),
+                    // Without an estimate the color can't be trusted at all
+                    variance: target.luminance_variance(index).unwrap_or(Float::INFINITY),
+                    albedo: target.aov(index, Aov::Albedo).map(Color::from),
+                    normal: target.aov(index, Aov::Normal).map(Vec3::from),
+                })
+            })
+            .collect();
+
+        let denoiser = Self {
+            sigma_spatial: self.sigma_spatial.max(Self::MIN_SIGMA),
+            sigma_color: self.sigma_color.max(Self::MIN_SIGMA),
+            sigma_albedo: self.sigma_albedo.max(Self::MIN_SIGMA),
+            sigma_normal: self.sigma_normal.max(Self::MIN_SIGMA),
+            ..*self
+        };
+
+        (0..target.width * target.height)
+            .into_par_iter()
+            .map(|index| {
+                denoiser.filter_pixel(
+                    &guides,
+                    target.width,
+                    target.height,
+                    index % target.width,
+                    index / target.width,
+                )
+            })
+            .collect()
+    }
+
+    fn filter_pixel(
+        &self,
+        guides: &[Option<Guide>],
+        width: usize,
+        height: usize,
+        x: usize,
+        y: usize,
+    ) -> Color {
+        let center = match &guides[x + y * width] {
+            Some(center) => center,
+            None => return Color::new(0.0, 0.0, 0.0),
+        };
+        let mut sum = Color::new(0.0, 0.0, 0.0);
+        let mut total_weight = 0.0;
+
+        for ny in y.saturating_sub(self.radius)..(y + self.radius + 1).min(height) {
+            for nx in x.saturating_sub(self.radius)..(x + self.radius + 1).min(width) {
+                let other = match &guides[nx + ny * width] {
+                    Some(other) => other,
+                    None => continue,
+                };
+
+                let dx = nx as Float - x as Float;
+                let dy = ny as Float - y as Float;
+                let mut exponent = (dx * dx + dy * dy) / (2.0 * self.sigma_spatial.powi(2));
+
+                // Noisy pixels are allowed to differ more
+                let difference = center.color.luminance() - other.color.luminance();
+                let noise = self.sigma_color.powi(2) * (center.variance + other.variance);
+                exponent += difference * difference / (2.0 * noise + 1e-8);
+
+                if let (Some(a), Some(b)) = (center.albedo, other.albedo) {
+                    let distance = (a.r - b.r).powi(2) + (a.g - b.g).powi(2) + (a.b - b.b).powi(2);
+                    exponent += distance / (2.0 * self.sigma_albedo.powi(2));
+                }
+                if let (Some(a), Some(b)) = (center.normal, other.normal) {
+                    exponent += (a - b).magnitude2() / (2.0 * self.sigma_normal.powi(2));
+                }
+
+                let weight = (-exponent).exp();
+                sum = sum + other.color * weight;
+                total_weight += weight;
+            }
+        }
+
+        // The center pixel always has a weight of one
+        sum * (1.0 / total_weight)
+    }
+}
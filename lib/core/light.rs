@@ -0,0 +1,211 @@
+use cgmath::InnerSpace;
+
+use crate::utils::{
+    color::Color,
+    math::power_heuristic,
+    ray::{HitRecord, Ray},
+    sample::Sampler,
+    types::*,
+};
+
+use super::scene::{MaterialHandle, ObjectHandle, Scene};
+use super::traits::{Hittable, Material};
+
+/// Offset used on both ends of shadow rays to avoid self intersection
+const SHADOW_EPSILON: Float = 0.0001;
+
+/// Point picked on the surface of a light
+#[derive(Clone, Copy)]
+pub struct LightSample {
+    pub point: Vec3,
+    /// Outward surface normal at the point
+    pub normal: Vec3,
+    /// Probability density of the sample with respect to solid angle, as seen
+    /// from the point being lit
+    pub pdf: Float,
+}
+
+/// All objects of a scene with an emissive material which can be sampled, plus
+/// the background if it is a light, used for next event estimation
+#[derive(Default)]
+pub struct LightList {
+    lights: Vec<ObjectHandle>,
+    /// For every object of the scene, whether it is in the list
+    is_light: Vec<bool>,
+    environment: bool,
+}
+
+impl LightList {
+    pub fn build(scene: &Scene) -> Self {
+        let is_light: Vec<bool> = scene
+            .objects
+            .iter()
+            .map(|object| object.samples_light() && scene.material(object.material()).is_emissive())
+            .collect();
+
+        let lights = is_light
+            .iter()
+            .enumerate()
+            .filter(|(_, is_light)| **is_light)
+            .map(|(index, _)| ObjectHandle(index))
+            .collect();
+
+        Self {
+            lights,
+            is_light,
+            environment: scene.background.is_light(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of lights, counting the background as one
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.lights.len() + usize::from(self.environment)
+    }
+
+    /// Returns true if the object is sampled as a light, the light of other
+    /// emissive objects is only found by hitting them
+    #[inline]
+    pub fn contains(&self, object: usize) -> bool {
+        self.is_light.get(object).copied().unwrap_or(false)
+    }
+
+    /// Returns true if the background is sampled as a light
+    #[inline]
+    pub fn has_environment(&self) -> bool {
+        self.environment
+    }
+
+    /// Probability density with respect to solid angle of `sample_direct`
+    /// picking the point of `hit`, as seen from the origin of `ray`
+    pub fn pdf(&self, scene: &Scene, ray: &Ray, hit: &HitRecord<MaterialHandle>) -> Float {
+        if !self.contains(hit.object) {
+            return 0.0;
+        }
+
+        let object = scene.object(ObjectHandle(hit.object));
+        object.light_pdf(ray.origin, ray.time, hit) / self.len() as Float
+    }
+
+    /// Probability density with respect to solid angle of `sample_direct`
+    /// picking the background in `direction`
+    pub fn environment_pdf(&self, scene: &Scene, direction: Vec3) -> Float {
+        if !self.environment {
+            return 0.0;
+        }
+
+        scene.background.pdf(direction) / self.len() as Float
+    }
+
+    /// Estimates the light scattered back along the ray that arrives directly
+    /// from the lights, using a single shadow ray towards a randomly picked light
+    pub fn sample_direct(
+        &self,
+        scene: &Scene,
+        world: &dyn Hittable,
+        ray: &Ray,
+        hit: &HitRecord<MaterialHandle>,
+        material: &dyn Material,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        match self.sample_unweighted(scene, world, ray, hit, material, sampler) {
+            Some((light, _, _)) => light,
+            None => Color::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Like `sample_direct`, but weighted with the power heuristic against the
+    /// chance of the material sampling the same direction
+    pub fn sample_direct_mis(
+        &self,
+        scene: &Scene,
+        world: &dyn Hittable,
+        ray: &Ray,
+        hit: &HitRecord<MaterialHandle>,
+        material: &dyn Material,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        match self.sample_unweighted(scene, world, ray, hit, material, sampler) {
+            Some((light, direction, pdf)) => {
+                light * power_heuristic(pdf, material.pdf(ray, hit, direction))
+            }
+            None => Color::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Returns the light arriving from a randomly picked light divided by the
+    /// probability of picking it, together with its direction and that
+    /// probability. None if the light is blocked or doesn't contribute
+    fn sample_unweighted(
+        &self,
+        scene: &Scene,
+        world: &dyn Hittable,
+        ray: &Ray,
+        hit: &HitRecord<MaterialHandle>,
+        material: &dyn Material,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Vec3, Float)> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let index = ((sampler.next_1d() * self.len() as Float) as usize).min(self.len() - 1);
+        let (to_light, normal, pdf, tmax) = if index < self.lights.len() {
+            let light = scene.object(self.lights[index]);
+            let sample = light.sample_light(hit.point, ray.time, sampler)?;
+            (
+                sample.point - hit.point,
+                sample.normal,
+                sample.pdf,
+                1.0 - SHADOW_EPSILON,
+            )
+        } else {
+            let (direction, pdf) = scene.background.sample_direction(sampler)?;
+            (direction, -direction, pdf, Float::INFINITY)
+        };
+
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        let direction = to_light.normalize();
+        let bsdf = material.eval(ray, hit, direction);
+        if bsdf.is_black() {
+            return None;
+        }
+
+        // Anything in between casts a shadow
+        let shadow_ray = Ray::with_time(hit.point, to_light, ray.time);
+        if world
+            .hit(&shadow_ray, SHADOW_EPSILON, tmax, sampler)
+            .is_some()
+        {
+            return None;
+        }
+
+        let emitted = if index < self.lights.len() {
+            let light = scene.object(self.lights[index]);
+            let light_hit = HitRecord::new(
+                hit.point + to_light,
+                normal,
+                1.0,
+                &shadow_ray,
+                light.material(),
+            );
+            scene
+                .material(light.material())
+                .emitted(&shadow_ray, &light_hit)
+        } else {
+            scene.background.sample(&shadow_ray)
+        };
+
+        // Divided by the probability of picking this light and this point on it
+        let pdf = pdf / self.len() as Float;
+        Some((bsdf * emitted * (1.0 / pdf), direction, pdf))
+    }
+}
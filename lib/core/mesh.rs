@@ -2,6 +2,7 @@ use crate::utils::{
     aabb::{Bounded, AABB},
     math::degrees_to_radians,
     ray::{HitRecord, Ray},
+    sample::{sample_triangle, Sampler},
     types::{Float, Vec3},
 };
 use cgmath::InnerSpace;
@@ -11,12 +12,13 @@ use std::fs::File;
 use std::io::BufReader;
 
 use super::{
-    bvh::{BoundsCollection, BvhNode},
+    bvh::{BoundsCollection, BvhNode, BvhSettings, LinearBvh},
+    light::LightSample,
     scene::MaterialHandle,
     traits::{Hittable, Object},
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Triangle {
     vertices: [u32; 3],
     normal: Vec3,
@@ -33,7 +35,9 @@ pub struct Mesh {
     vertices: Vec<Vertex>,
     bounds: AABB,
     triangles: Vec<Triangle>,
-    bvh_root: BvhNode,
+    /// Running sum of the triangle areas, used to pick triangles by area
+    area_cdf: Vec<Float>,
+    bvh: LinearBvh,
     material: MaterialHandle,
 }
 
@@ -81,6 +85,8 @@ impl Mesh {
         material: MaterialHandle,
     ) -> Self {
         let mut triangles = Vec::<Triangle>::new();
+        let mut area_cdf = Vec::<Float>::new();
+        let mut area = 0.0;
 
         for triangle in indices.chunks_exact(3) {
             let e1 =
@@ -89,10 +95,13 @@ impl Mesh {
                 vertices[triangle[2] as usize].position - vertices[triangle[1] as usize].position;
             let normal = e2.cross(e1).normalize();
 
-            triangles.push(Triangle {
+            let triangle = Triangle {
                 vertices: [triangle[0], triangle[1], triangle[2]],
                 normal,
-            })
+            };
+            area += triangle.area(&vertices);
+            area_cdf.push(area);
+            triangles.push(triangle);
         }
 
         // Calculate bounding box
@@ -110,20 +119,54 @@ impl Mesh {
         Self {
             vertices,
             triangles,
+            area_cdf,
             material,
-            bvh_root: BvhNode::None,
+            bvh: LinearBvh::default(),
             bounds,
         }
     }
 
     pub fn build_bvh(&mut self) {
-        self.bvh_root = BvhNode::from_list(&mut self.objects(), self);
+        self.build_bvh_with(&BvhSettings::default());
+    }
+
+    pub fn build_bvh_with(&mut self, settings: &BvhSettings) {
+        let mut order = self.objects();
+        let root = BvhNode::from_list(&mut order, self, settings);
+
+        // Store the triangles in the order the leaves reference them, so the
+        // triangles of a leaf are next to each other in memory
+        self.triangles = order
+            .iter()
+            .map(|&index| self.triangles[index as usize])
+            .collect();
+        self.area_cdf = self
+            .triangles
+            .iter()
+            .scan(0.0, |sum, triangle| {
+                *sum += triangle.area(&self.vertices);
+                Some(*sum)
+            })
+            .collect();
+
+        self.bvh = LinearBvh::flatten(root, self.objects());
+    }
+
+    /// Total surface area of all triangles
+    pub fn area(&self) -> Float {
+        self.area_cdf.last().copied().unwrap_or(0.0)
     }
 }
 
 impl Hittable for Mesh {
-    fn hit(&self, ray: &Ray, tmin: Float, tmax: Float) -> Option<HitRecord<MaterialHandle>> {
-        return self.bvh_root.hit(ray, tmin, tmax, self);
+    fn hit(
+        &self,
+        ray: &Ray,
+        tmin: Float,
+        tmax: Float,
+        sampler: &mut dyn Sampler,
+    ) -> Option<HitRecord<MaterialHandle>> {
+        return self.bvh.hit(ray, tmin, tmax, self, sampler);
 
         //let mut result = None;
         //let mut closest_so_far = tmax;
@@ -144,6 +187,14 @@ impl Hittable for Mesh {
 }
 
 impl Triangle {
+    fn area(&self, vertices: &[Vertex]) -> Float {
+        let e1 = vertices[self.vertices[0] as usize].position
+            - vertices[self.vertices[1] as usize].position;
+        let e2 = vertices[self.vertices[2] as usize].position
+            - vertices[self.vertices[1] as usize].position;
+        0.5 * e2.cross(e1).magnitude()
+    }
+
     fn hit(
         &self,
         ray: &Ray,
@@ -226,6 +277,7 @@ impl BoundsCollection for Mesh {
         ray: &Ray,
         tmin: Float,
         tmax: Float,
+        _sampler: &mut dyn Sampler,
     ) -> Option<HitRecord<MaterialHandle>> {
         self.triangles[handle as usize].hit(ray, tmin, tmax, self.material, self)
     }
@@ -242,4 +294,57 @@ impl Object for Mesh {
     fn material(&self) -> MaterialHandle {
         self.material
     }
+
+    fn samples_light(&self) -> bool {
+        true
+    }
+
+    fn sample_light(
+        &self,
+        origin: Vec3,
+        _time: Float,
+        sampler: &mut dyn Sampler,
+    ) -> Option<LightSample> {
+        let area = self.area();
+        if area <= 0.0 {
+            return None;
+        }
+
+        // Pick a triangle with probability proportional to its area
+        let target = sampler.next_1d() * area;
+        let index = self
+            .area_cdf
+            .partition_point(|&sum| sum <= target)
+            .min(self.triangles.len() - 1);
+        let triangle = &self.triangles[index];
+
+        let (u, v) = sample_triangle(sampler.next_2d());
+        let v0 = self.vertices[triangle.vertices[0] as usize].position;
+        let v1 = self.vertices[triangle.vertices[1] as usize].position;
+        let v2 = self.vertices[triangle.vertices[2] as usize].position;
+        let point = v0 + (v1 - v0) * u + (v2 - v0) * v;
+
+        // Convert the uniform area density to solid angle
+        let to_point = point - origin;
+        let cosine = triangle.normal.dot(to_point.normalize()).abs();
+        if cosine <= 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            point,
+            normal: triangle.normal,
+            pdf: to_point.magnitude2() / (cosine * area),
+        })
+    }
+
+    fn light_pdf(&self, origin: Vec3, _time: Float, hit: &HitRecord<MaterialHandle>) -> Float {
+        let to_point = hit.point - origin;
+        let cosine = hit.normal.dot(to_point.normalize()).abs();
+        if cosine <= 0.0 {
+            return 0.0;
+        }
+
+        to_point.magnitude2() / (cosine * self.area())
+    }
 }
@@ -1,6 +1,13 @@
+pub mod animation;
+pub mod aov;
 pub mod bvh;
 pub mod camera;
+pub mod denoise;
+pub mod light;
 pub mod mesh;
+pub mod output;
 pub mod render;
 pub mod scene;
+pub mod tiles;
+pub mod tonemap;
 pub mod traits;
@@ -0,0 +1,307 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+use image::{codecs::hdr::HdrEncoder, ImageError, Rgb, RgbImage, RgbaImage};
+
+use crate::utils::color::Color;
+
+use super::aov::Aov;
+use super::render::RenderTarget;
+
+/// Image formats a render target can be saved as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// 8-bit gamma corrected
+    Png,
+    /// 8-bit gamma corrected, no alpha channel
+    Jpeg,
+    /// 32-bit float linear OpenEXR
+    Exr,
+    /// Radiance RGBE linear
+    Hdr,
+    /// 32-bit float linear portable float map
+    Pfm,
+}
+
+impl OutputFormat {
+    /// Guess the format from the extension of a path
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match &extension[..] {
+            "png" => Some(Self::Png),
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            "exr" => Some(Self::Exr),
+            "hdr" => Some(Self::Hdr),
+            "pfm" => Some(Self::Pfm),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the format stores linear floating point radiance
+    pub fn is_hdr(&self) -> bool {
+        matches!(self, Self::Exr | Self::Hdr | Self::Pfm)
+    }
+}
+
+#[derive(Debug)]
+pub enum OutputError {
+    UnknownFormat(String),
+    /// The image can't be loaded back as linear radiance
+    NotLinear(String),
+    Malformed(String),
+    Io(std::io::Error),
+    Image(ImageError),
+    Exr(exr::error::Error),
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputError::UnknownFormat(path) => write!(f, "unknown image format for '{}'", path),
+            OutputError::NotLinear(path) => write!(f, "'{}' is not a linear image", path),
+            OutputError::Malformed(message) => write!(f, "malformed image: {}", message),
+            OutputError::Io(error) => write!(f, "{}", error),
+            OutputError::Image(error) => write!(f, "{}", error),
+            OutputError::Exr(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for OutputError {}
+
+impl From<std::io::Error> for OutputError {
+    fn from(error: std::io::Error) -> Self {
+        OutputError::Io(error)
+    }
+}
+
+impl From<ImageError> for OutputError {
+    fn from(error: ImageError) -> Self {
+        OutputError::Image(error)
+    }
+}
+
+impl From<exr::error::Error> for OutputError {
+    fn from(error: exr::error::Error) -> Self {
+        OutputError::Exr(error)
+    }
+}
+
+/// Save a render target, picking the format from the file extension
+pub fn save<P: AsRef<Path>>(target: &RenderTarget, path: P) -> Result<(), OutputError> {
+    let format = OutputFormat::from_path(&path)
+        .ok_or_else(|| OutputError::UnknownFormat(path.as_ref().display().to_string()))?;
+    save_as(target, path, format)
+}
+
+pub fn save_as<P: AsRef<Path>>(
+    target: &RenderTarget,
+    path: P,
+    format: OutputFormat,
+) -> Result<(), OutputError> {
+    match format {
+        OutputFormat::Png => save_ldr(target, path),
+        OutputFormat::Jpeg => save_ldr_rgb(target, path),
+        OutputFormat::Exr => save_exr(target, path),
+        OutputFormat::Hdr => save_hdr(target, path),
+        OutputFormat::Pfm => save_pfm(target, path),
+    }
+}
+
+fn save_ldr<P: AsRef<Path>>(target: &RenderTarget, path: P) -> Result<(), OutputError> {
+    let image = RgbaImage::from_raw(
+        target.width as u32,
+        target.height as u32,
+        target.data.clone(),
+    )
+    .expect("render target has wrong buffer size");
+    image.save(path)?;
+    Ok(())
+}
+
+/// Save without the alpha channel, for formats that don't support one
+fn save_ldr_rgb<P: AsRef<Path>>(target: &RenderTarget, path: P) -> Result<(), OutputError> {
+    let image = RgbImage::from_fn(target.width as u32, target.height as u32, |x, y| {
+        let index = (x as usize + y as usize * target.width) * 4;
+        Rgb([
+            target.data[index],
+            target.data[index + 1],
+            target.data[index + 2],
+        ])
+    });
+    image.save(path)?;
+    Ok(())
+}
+
+fn save_exr<P: AsRef<Path>>(target: &RenderTarget, path: P) -> Result<(), OutputError> {
+    if !target.aovs.is_empty() {
+        return save_layered_exr(target, path);
+    }
+
+    exr::prelude::write_rgb_file(path, target.width, target.height, |x, y| {
+        let color = target.linear(x + y * target.width);
+        (color.r, color.g, color.b)
+    })?;
+    Ok(())
+}
+
+/// Writes the beauty image as the plain RGB channels, followed by every AOV as
+/// channels prefixed with its layer name, like `albedo.R`
+fn save_layered_exr<P: AsRef<Path>>(target: &RenderTarget, path: P) -> Result<(), OutputError> {
+    use exr::prelude::*;
+
+    let pixels = target.width * target.height;
+    let channel = |name: String, value: &dyn Fn(usize) -> f32| {
+        AnyChannel::new(
+            name.as_str(),
+            FlatSamples::F32((0..pixels).map(value).collect()),
+        )
+    };
+
+    let mut channels = Vec::new();
+    for (index, name) in ["R", "G", "B"].into_iter().enumerate() {
+        channels.push(channel(name.to_owned(), &|pixel| {
+            target.linear(pixel).data()[index]
+        }));
+    }
+
+    for aov in Aov::ALL {
+        for (index, name) in aov.channels().iter().enumerate() {
+            channels.push(channel(format!("{}.{}", aov.name(), name), &|pixel| {
+                target.aov(pixel, aov).map_or(0.0, |value| value[index])
+            }));
+        }
+    }
+
+    let layer = Layer::new(
+        (target.width, target.height),
+        LayerAttributes::default(),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels.into()),
+    );
+    Image::from_layer(layer).write().to_file(path)?;
+    Ok(())
+}
+
+fn save_hdr<P: AsRef<Path>>(target: &RenderTarget, path: P) -> Result<(), OutputError> {
+    let pixels: Vec<Rgb<f32>> = (0..target.width * target.height)
+        .map(|index| {
+            let color = target.linear(index);
+            Rgb([color.r, color.g, color.b])
+        })
+        .collect();
+
+    let writer = BufWriter::new(File::create(path)?);
+    HdrEncoder::new(writer).encode(&pixels[..], target.width, target.height)?;
+    Ok(())
+}
+
+fn save_pfm<P: AsRef<Path>>(target: &RenderTarget, path: P) -> Result<(), OutputError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    // Negative scale marks the data as little endian
+    write!(writer, "PF\n{} {}\n-1.0\n", target.width, target.height)?;
+
+    // Scanlines are stored bottom to top
+    for y in (0..target.height).rev() {
+        for x in 0..target.width {
+            for channel in target.linear(x + y * target.width).data() {
+                writer.write_all(&channel.to_le_bytes())?;
+            }
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Load a linear exr, hdr or pfm image, for example to tone map an earlier
+/// render again without tracing it
+pub fn load<P: AsRef<Path>>(path: P) -> Result<RenderTarget, OutputError> {
+    let display = path.as_ref().display().to_string();
+    match OutputFormat::from_path(&path) {
+        Some(OutputFormat::Pfm) => load_pfm(path),
+        Some(format) if format.is_hdr() => {
+            let image = image::open(path)?.into_rgb32f();
+            let pixels = image.pixels().map(|pixel| Color::from(pixel.0)).collect();
+            Ok(RenderTarget::from_linear(
+                image.width() as usize,
+                image.height() as usize,
+                pixels,
+            ))
+        }
+        Some(_) => Err(OutputError::NotLinear(display)),
+        None => Err(OutputError::UnknownFormat(display)),
+    }
+}
+
+fn load_pfm<P: AsRef<Path>>(path: P) -> Result<RenderTarget, OutputError> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut header = String::new();
+    for _ in 0..3 {
+        reader.read_line(&mut header)?;
+    }
+
+    let fields: Vec<&str> = header.split_whitespace().collect();
+    let (width, height, scale) = match fields[..] {
+        ["PF", width, height, scale] => (
+            width.parse::<usize>(),
+            height.parse::<usize>(),
+            scale.parse::<f32>(),
+        ),
+        _ => {
+            return Err(OutputError::Malformed(
+                "expected a color pfm header".to_owned(),
+            ))
+        }
+    };
+    let (width, height, scale) = match (width, height, scale) {
+        (Ok(width), Ok(height), Ok(scale)) => (width, height, scale),
+        _ => return Err(OutputError::Malformed("invalid pfm header".to_owned())),
+    };
+
+    // Checked before allocating, so a bad header can't ask for huge buffers
+    let remaining = reader
+        .get_ref()
+        .metadata()?
+        .len()
+        .saturating_sub(reader.stream_position()?);
+    let size = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(12))
+        .filter(|&size| size as u64 <= remaining)
+        .ok_or_else(|| {
+            OutputError::Malformed(format!(
+                "{}x{} pixels don't fit in the {} bytes of data",
+                width, height, remaining
+            ))
+        })?;
+
+    let mut bytes = vec![0; size];
+    reader.read_exact(&mut bytes)?;
+
+    // Negative scale marks the data as little endian
+    let channels: Vec<f32> = bytes
+        .chunks_exact(4)
+        .map(|chunk| {
+            let chunk = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            if scale < 0.0 {
+                f32::from_le_bytes(chunk)
+            } else {
+                f32::from_be_bytes(chunk)
+            }
+        })
+        .collect();
+
+    // Scanlines are stored bottom to top
+    let pixels = (0..height)
+        .rev()
+        .flat_map(|y| channels[y * width * 3..(y + 1) * width * 3].chunks_exact(3))
+        .map(|channel| Color::new(channel[0], channel[1], channel[2]))
+        .collect();
+
+    Ok(RenderTarget::from_linear(width, height, pixels))
+}
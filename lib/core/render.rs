@@ -1,127 +1,1137 @@
+use std::ops::{Range, RangeInclusive};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
 use cgmath::InnerSpace;
-use rand::thread_rng;
-use rand::{distributions::Uniform, prelude::Distribution};
-use rayon::iter::{IndexedParallelIterator, ParallelIterator};
-use rayon::slice::ParallelSliceMut;
 
-use crate::core::scene::RenderMode;
+use crate::core::scene::{Integrator, RenderMode, RenderSettings};
 use crate::materials::Lambertian;
-use crate::utils::{color::Color, ray::Ray, types::*};
+use crate::utils::sample::{SampleIndex, Sampler};
+use crate::utils::{color::Color, math::power_heuristic, ray::Ray, types::*};
 
+use super::aov::{Aov, AovSamples};
+use super::bvh::LinearBvh;
+use super::camera::{Camera, RayOrigin};
+use super::denoise::Denoiser;
+use super::light::LightList;
+use super::scene::MaterialHandle;
+use super::tiles::{generate_tiles, Tile, TileReport};
+use super::tonemap::ToneMapping;
 use super::traits::Material;
 use super::{scene::Scene, traits::Hittable};
+use crate::utils::ray::HitRecord;
+
+/// Material every object gets in clay mode
+const CLAY: Lambertian = Lambertian::new(Color::new(0.8, 0.8, 0.8));
 
 pub struct RenderTarget {
     pub width: usize,
     pub height: usize,
+    /// Tone mapped 8-bit sRGB pixels with alpha, ready for display
     pub data: Vec<u8>,
+    /// Sum of all linear radiance samples taken for every pixel
+    pub accumulation: Vec<Color>,
+    /// Sum of the squared luminance of all samples taken for every pixel, used
+    /// to estimate their variance
+    pub luminance_squares: Vec<Float>,
+    /// Number of samples accumulated for every pixel
+    pub pixel_samples: Vec<u32>,
+    /// Largest number of samples accumulated for any pixel, the sample count
+    /// heat map is scaled by it
+    pub max_pixel_samples: u32,
+    /// Number of samples per pixel of all finished passes
+    pub samples: u32,
+    /// Set once adaptive sampling has no pixels left to sample
+    pub converged: bool,
+    /// What the display buffer shows
+    pub view: TargetView,
+    /// How the image is turned into the display buffer
+    pub tone_mapping: ToneMapping,
+    /// AOV sums for every pixel, empty unless the render settings ask for them
+    pub aovs: Vec<AovSamples>,
+    /// Filter run over the image after every pass, if any
+    pub denoiser: Option<Denoiser>,
+    /// Output of the denoiser, empty while it is off or hasn't run yet
+    pub denoised: Vec<Color>,
     pub request_redraw: bool,
 }
 
+/// Contents of the display buffer of a render target
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetView {
+    /// The rendered image
+    Image,
+    /// Heat map of the number of samples taken for every pixel
+    SampleCount,
+    /// One of the AOVs, black if they weren't rendered
+    Aov(Aov),
+}
+
+/// Samples traced for one pixel of a tile
+#[derive(Debug, Clone, Copy)]
+pub struct PixelSamples {
+    /// Sum of the radiance of the samples
+    pub color: Color,
+    /// Sum of the squared luminance of the samples
+    pub luminance_squares: Float,
+    pub samples: u32,
+    /// Sums of the AOVs, if they are rendered
+    pub aovs: Option<AovSamples>,
+}
+
 impl RenderTarget {
     pub fn new(width: usize, height: usize) -> Self {
         Self {
             width,
             height,
             data: vec![0; width * height * 4],
+            accumulation: vec![Color::new(0.0, 0.0, 0.0); width * height],
+            luminance_squares: vec![0.0; width * height],
+            pixel_samples: vec![0; width * height],
+            max_pixel_samples: 0,
+            samples: 0,
+            converged: false,
+            view: TargetView::Image,
+            tone_mapping: ToneMapping::default(),
+            aovs: Vec::new(),
+            denoiser: None,
+            denoised: Vec::new(),
             request_redraw: false,
         }
     }
-}
 
-fn trace_ray(scene: &Scene, world: &dyn Hittable, ray: &Ray, depth: u8) -> Color {
-    // Base condition
-    if depth >= scene.settings.max_ray_depth {
-        return Color::new(0.0, 0.0, 0.0);
+    /// Target holding a finished linear image, as if it was rendered with a
+    /// single sample per pixel
+    pub fn from_linear(width: usize, height: usize, pixels: Vec<Color>) -> Self {
+        assert_eq!(pixels.len(), width * height, "wrong number of pixels");
+
+        let mut target = Self::new(width, height);
+        target.accumulation = pixels;
+        target.pixel_samples.fill(1);
+        target.max_pixel_samples = 1;
+        target.samples = 1;
+        target.resolve();
+        target
     }
 
-    if let Some(hit) = world.hit(ray, 0.00001, Float::INFINITY) {
-        let (attenuation, scattered) = match scene.settings.mode {
-            RenderMode::Full => scene.material(hit.material).scatter(ray, &hit),
-            RenderMode::Clay => Lambertian::new(Color::new(0.8, 0.8, 0.8)).scatter(ray, &hit),
-            RenderMode::Normal => {
-                let normal = 0.5 * (hit.normal.normalize() + Vec3::new(1.0, 1.0, 1.0));
-                return Color::new(normal.x, normal.y, normal.z);
+    /// Throw away all accumulated samples, the display buffer is kept
+    /// until the next pass overwrites it
+    pub fn clear(&mut self) {
+        self.accumulation.fill(Color::new(0.0, 0.0, 0.0));
+        self.luminance_squares.fill(0.0);
+        self.pixel_samples.fill(0);
+        self.max_pixel_samples = 0;
+        self.aovs.clear();
+        self.denoised.clear();
+        self.samples = 0;
+        self.converged = false;
+    }
+
+    /// Adds the samples of a finished tile and updates the display buffer for it
+    pub fn add_tile(&mut self, tile: &Tile, pixels: &[PixelSamples]) {
+        for (row, samples) in pixels.chunks_exact(tile.width).enumerate() {
+            let start = tile.x + (tile.y + row) * self.width;
+
+            for (index, samples) in (start..start + tile.width).zip(samples) {
+                self.accumulation[index] = self.accumulation[index] + samples.color;
+                self.luminance_squares[index] += samples.luminance_squares;
+                self.pixel_samples[index] += samples.samples;
+                self.max_pixel_samples = self.max_pixel_samples.max(self.pixel_samples[index]);
+
+                if let Some(aovs) = &samples.aovs {
+                    if self.aovs.is_empty() {
+                        self.aovs = vec![AovSamples::new(); self.width * self.height];
+                    }
+                    self.aovs[index].add(aovs);
+                }
+
+                self.resolve_pixel(index);
+            }
+        }
+
+        self.request_redraw = true;
+    }
+
+    /// Marks a pass of `samples` samples per pixel as finished
+    pub fn finish_pass(&mut self, samples: u32) {
+        self.samples += samples;
+
+        if self.denoiser.is_some() {
+            self.denoise();
+        } else if self.view == TargetView::SampleCount {
+            // The heat map is scaled by the number of samples, so all of it changes
+            self.resolve();
+        }
+    }
+
+    /// Turns the denoiser on or off and redraws the display buffer
+    pub fn set_denoiser(&mut self, denoiser: Option<Denoiser>) {
+        self.denoiser = denoiser;
+        self.denoise();
+    }
+
+    /// Runs the denoiser over the accumulated samples, if it is on
+    fn denoise(&mut self) {
+        self.denoised.clear();
+        if let Some(denoiser) = self.denoiser {
+            if self.samples > 0 {
+                self.denoised = denoiser.apply(self);
             }
-            RenderMode::Random => {
-                return Color::new(0.0, 0.0, 0.0);
+        }
+
+        self.resolve();
+    }
+
+    /// Changes the tone mapping and redraws the display buffer, the accumulated
+    /// samples are kept
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+        self.tone_mapping = tone_mapping;
+        self.resolve();
+    }
+
+    /// Switches what the display buffer shows and redraws it
+    pub fn set_view(&mut self, view: TargetView) {
+        self.view = view;
+        self.resolve();
+    }
+
+    /// Recomputes the display buffer from the accumulated samples
+    pub fn resolve(&mut self) {
+        for index in 0..self.width * self.height {
+            self.resolve_pixel(index);
+        }
+
+        self.request_redraw = true;
+    }
+
+    fn resolve_pixel(&mut self, index: usize) {
+        let color = match self.view {
+            TargetView::Image => self.tone_mapping.apply(self.linear(index)),
+            TargetView::SampleCount => {
+                let fraction =
+                    self.pixel_samples[index] as Float / self.max_pixel_samples.max(1) as Float;
+                heat_map(fraction)
             }
+            TargetView::Aov(aov) => match self.aovs.get(index) {
+                Some(aovs) => {
+                    let color = aovs.display(aov, self.pixel_samples[index]);
+                    match aov {
+                        Aov::DirectDiffuse
+                        | Aov::IndirectDiffuse
+                        | Aov::DirectSpecular
+                        | Aov::IndirectSpecular => self.tone_mapping.apply(color),
+                        _ => color,
+                    }
+                }
+                None => Color::new(0.0, 0.0, 0.0),
+            },
         };
 
-        if let Some(scattered) = scattered {
-            attenuation * trace_ray(scene, world, &scattered, depth + 1)
-        } else {
-            attenuation
+        // Write raw data to buffer
+        self.data[index * 4..index * 4 + 4].copy_from_slice(&color.into_raw());
+    }
+
+    /// Linear radiance of a pixel, denoised if the denoiser is on
+    #[inline]
+    pub fn linear(&self, index: usize) -> Color {
+        match self.denoised.get(index) {
+            Some(color) => *color,
+            None => self.noisy(index),
         }
-    } else {
-        scene.background.sample(ray)
+    }
+
+    /// Linear radiance of a pixel, averaged over all accumulated samples
+    #[inline]
+    pub fn noisy(&self, index: usize) -> Color {
+        match self.pixel_samples[index] {
+            0 => Color::new(0.0, 0.0, 0.0),
+            samples => self.accumulation[index] * (1.0 / samples as Float),
+        }
+    }
+
+    /// Averaged channels of an AOV of a pixel, None if AOVs weren't rendered
+    pub fn aov(&self, index: usize, aov: Aov) -> Option<[Float; 3]> {
+        let aovs = self.aovs.get(index)?;
+        Some(aovs.value(aov, self.pixel_samples[index]))
+    }
+
+    /// Variance of the mean luminance of a pixel, estimated from its samples.
+    /// None while there are too few samples to tell
+    pub fn luminance_variance(&self, index: usize) -> Option<Float> {
+        let samples = self.pixel_samples[index];
+        if samples < 2 {
+            return None;
+        }
+
+        let n = samples as Float;
+        let mean = self.accumulation[index].luminance() / n;
+        let variance = ((self.luminance_squares[index] / n - mean * mean) * n / (n - 1.0)).max(0.0);
+        Some(variance / n)
+    }
+
+    /// Standard error of the mean luminance of a pixel, as it shows up after
+    /// gamma correction. Infinite while there are too few samples to tell
+    pub fn pixel_noise(&self, index: usize) -> Float {
+        let variance = match self.luminance_variance(index) {
+            Some(variance) => variance,
+            None => return Float::INFINITY,
+        };
+
+        // Gamma correction takes the square root, whose slope scales the error
+        let mean = self.noisy(index).luminance();
+        variance.sqrt() / (2.0 * mean.max(1e-4).sqrt())
+    }
+
+    /// For every pixel the indices of the samples to take in a pass of
+    /// `samples` samples per pixel, or None if the pixel is left out of it.
+    /// Without adaptive sampling every pixel gets `samples` samples, with it
+    /// the samples of converged pixels are shared out among the others, as far
+    /// as `max_samples` allows
+    pub fn pending_samples(
+        &self,
+        settings: &RenderSettings,
+        samples: u32,
+    ) -> Vec<Option<Range<u32>>> {
+        if !settings.adaptive_sampling {
+            return self
+                .pixel_samples
+                .iter()
+                .map(|&taken| Some(taken..taken + samples))
+                .collect();
+        }
+
+        // Pixels outside of the region aren't rendered, so they don't count
+        let rendered = |index: usize| {
+            let (x, y) = (index % self.width, index / self.width);
+            settings.region.is_none_or(|region| region.contains(x, y))
+        };
+        let pixels = (0..self.width * self.height)
+            .filter(|&index| rendered(index))
+            .count();
+
+        let active: Vec<bool> = (0..self.width * self.height)
+            .map(|index| {
+                let taken = self.pixel_samples[index];
+                rendered(index)
+                    && taken < settings.max_samples
+                    && (taken < settings.min_samples
+                        || self.pixel_noise(index) >= settings.noise_threshold)
+            })
+            .collect();
+
+        // The pass costs the same as without adaptive sampling
+        let active_count = active.iter().filter(|&&active| active).count().max(1);
+        let share =
+            (samples as u64 * pixels as u64 / active_count as u64).min(u32::MAX as u64) as u32;
+
+        active
+            .iter()
+            .zip(&self.pixel_samples)
+            .map(|(&active, &taken)| {
+                let count = share.min(settings.max_samples - taken);
+                active.then_some(taken..taken + count)
+            })
+            .collect()
     }
 }
 
+/// Maps zero to dark blue, half to red and one to pale yellow
+fn heat_map(value: Float) -> Color {
+    let stops = [
+        Color::new(0.0, 0.0, 0.2),
+        Color::new(0.9, 0.1, 0.1),
+        Color::new(1.0, 1.0, 0.6),
+    ];
+
+    let position = value * (stops.len() - 1) as Float;
+    let index = (position as usize).min(stops.len() - 2);
+    let t = position - index as Float;
+    stops[index] * (1.0 - t) + stops[index + 1] * t
+}
+
+/// Adds a single pass of `samples_per_pixel` samples to the target
 pub fn render(target: &mut RenderTarget, scene: &Scene) {
-    target.request_redraw = true;
+    render_with_progress(target, scene, |_| {});
+}
+
+/// Adds a single pass of `samples_per_pixel` samples to the target, calling
+/// `progress` every time a tile is finished
+pub fn render_with_progress<F>(target: &mut RenderTarget, scene: &Scene, progress: F)
+where
+    F: Fn(&TileReport) + Sync,
+{
+    with_prepared(scene, |prepared| {
+        render_pass(
+            target,
+            prepared,
+            scene.camera(),
+            scene.settings.samples_per_pixel,
+            progress,
+        );
+    });
+}
 
-    // Build up the scene
-    let ray_origin = scene.camera.ray_origin();
+/// Keeps adding passes of `samples_per_pixel` samples until the target holds
+/// `total_samples` samples per pixel, adaptive sampling has converged, or
+/// `keep_going` returns false after a pass.
+/// `progress` is called for every finished tile, together with the fraction of
+/// `total_samples` done so far
+pub fn render_progressive<P, K>(
+    target: &mut RenderTarget,
+    scene: &Scene,
+    total_samples: u32,
+    progress: P,
+    keep_going: K,
+) where
+    P: Fn(&TileReport, Float) + Sync,
+    K: FnMut(&RenderTarget) -> bool,
+{
+    with_prepared(scene, |prepared| {
+        render_passes(
+            target,
+            prepared,
+            scene.camera(),
+            total_samples,
+            progress,
+            keep_going,
+        );
+    });
+}
+
+/// Renders every frame in `frames` progressively like `render_progressive`,
+/// with the camera following its track. The target is cleared before every
+/// frame and handed to `finished` after it, the first error it returns stops
+/// the sequence. Only the camera moves between frames, so the Bvh tree and
+/// the light list are built once for all of them. `keep_going` only ends the
+/// passes of the current frame
+pub fn render_sequence<P, K, F, E>(
+    target: &mut RenderTarget,
+    scene: &Scene,
+    frames: RangeInclusive<u32>,
+    total_samples: u32,
+    progress: P,
+    mut keep_going: K,
+    mut finished: F,
+) -> Result<(), E>
+where
+    P: Fn(u32, &TileReport, Float) + Sync,
+    K: FnMut(&RenderTarget) -> bool,
+    F: FnMut(u32, &mut RenderTarget) -> Result<(), E>,
+{
+    with_prepared(scene, |prepared| {
+        for frame in frames {
+            let camera = scene.camera().at_frame(frame as Float);
+            target.clear();
+            render_passes(
+                target,
+                prepared,
+                &camera,
+                total_samples,
+                |report, fraction| progress(frame, report, fraction),
+                &mut keep_going,
+            );
+            finished(frame, target)?;
+        }
 
-    let bvh = scene.build_bvh();
+        Ok(())
+    })
+}
+
+/// Scene together with the acceleration structures built for it, which stay
+/// valid for as long as the objects don't change
+struct PreparedScene<'a> {
+    scene: &'a Scene,
+    world: &'a dyn Hittable,
+    lights: &'a LightList,
+}
+
+fn with_prepared<R, F>(scene: &Scene, f: F) -> R
+where
+    F: FnOnce(&PreparedScene) -> R,
+{
+    let bvh = scene.bvh();
+    let bvh = LockedBvh { scene, bvh: &bvh };
     let world: &dyn Hittable = if scene.settings.enable_bvh_tree {
         &bvh
     } else {
         scene
     };
+    let lights = LightList::build(scene);
+
+    f(&PreparedScene {
+        scene,
+        world,
+        lights: &lights,
+    })
+}
+
+fn render_passes<P, K>(
+    target: &mut RenderTarget,
+    prepared: &PreparedScene,
+    camera: &Camera,
+    total_samples: u32,
+    progress: P,
+    mut keep_going: K,
+) where
+    P: Fn(&TileReport, Float) + Sync,
+    K: FnMut(&RenderTarget) -> bool,
+{
+    while target.samples < total_samples && !target.converged {
+        let done = target.samples;
+        let samples = prepared
+            .scene
+            .settings
+            .samples_per_pixel
+            .min(total_samples - done)
+            .max(1);
 
-    // Computationally heavy task closure
-    let calculate_pixel = |(index, pixel): (usize, &mut [u8])| {
-        let x = index % target.width;
-        let y = index / target.width;
+        render_pass(target, prepared, camera, samples, |report| {
+            let fraction = report.finished as Float / report.total as Float;
+            progress(
+                report,
+                (done as Float + samples as Float * fraction) / total_samples as Float,
+            );
+        });
 
-        // Set up rng
-        let mut rng = thread_rng();
-        let range = Uniform::from(0.0..=1.0);
+        if !keep_going(target) {
+            break;
+        }
+    }
+}
+
+fn render_pass<F>(
+    target: &mut RenderTarget,
+    prepared: &PreparedScene,
+    camera: &Camera,
+    samples: u32,
+    progress: F,
+) where
+    F: Fn(&TileReport) + Sync,
+{
+    let scene = prepared.scene;
+    let pending = target.pending_samples(&scene.settings, samples);
+    if pending.iter().all(Option::is_none) {
+        target.converged = true;
+        return;
+    }
+
+    let pass = PassContext {
+        scene,
+        world: prepared.world,
+        lights: prepared.lights,
+        ray_origin: camera.ray_origin(),
+        width: target.width,
+        height: target.height,
+        pending: &pending,
+    };
 
-        let mut color = Color::new(0.0, 0.0, 0.0);
+    let tiles = pass.tiles();
+    let finished = AtomicUsize::new(0);
+    let cancelled = AtomicBool::new(false);
+    let target = Mutex::new(&mut *target);
 
-        // Run for N samples
-        for _ in 0..scene.settings.samples_per_pixel {
-            // UV coordinates
-            let u = (x as Float + range.sample(&mut rng)) / (target.width - 1) as Float;
-            let v = (y as Float + range.sample(&mut rng)) / (target.height - 1) as Float;
+    for_each_tile(&tiles, scene.settings.enable_multithreading, |tile| {
+        let now = Instant::now();
+        let pixels = pass.trace_tile(tile, &cancelled);
+        let duration = now.elapsed();
 
-            // Cast ray
-            let mut ray_color = trace_ray(&scene, world, &ray_origin.get_ray(u, v), 0);
+        target.lock().unwrap().add_tile(tile, &pixels);
 
-            // Prevent fireflies
-            ray_color.r = ray_color.r.clamp(0.0, scene.settings.clamp_indirect);
-            ray_color.g = ray_color.g.clamp(0.0, scene.settings.clamp_indirect);
-            ray_color.b = ray_color.b.clamp(0.0, scene.settings.clamp_indirect);
+        progress(&TileReport {
+            tile: *tile,
+            duration,
+            finished: finished.fetch_add(1, Ordering::Relaxed) + 1,
+            total: tiles.len(),
+        });
+    });
 
-            color = color + ray_color;
+    target.into_inner().unwrap().finish_pass(samples);
+}
+
+/// Hands the tiles out in order, either to the rayon thread pool or one by one
+/// on the current thread
+fn for_each_tile<F>(tiles: &[Tile], multithreaded: bool, render_tile: F)
+where
+    F: Fn(&Tile) + Sync,
+{
+    if multithreaded {
+        // Fifo so the tiles get picked up in the order they were generated
+        rayon::scope_fifo(|scope| {
+            for tile in tiles {
+                let render_tile = &render_tile;
+                scope.spawn_fifo(move |_| render_tile(tile));
+            }
+        });
+    } else {
+        tiles.iter().for_each(render_tile);
+    }
+}
+
+/// Light arriving along a camera ray, split up by how often it was scattered
+/// and by the kind of the first scattering event
+struct PathRadiance {
+    /// Emission and background seen directly
+    emitted: Color,
+    direct_diffuse: Color,
+    indirect_diffuse: Color,
+    direct_specular: Color,
+    indirect_specular: Color,
+    /// Whether the first bounce was specular
+    specular: bool,
+}
+
+impl PathRadiance {
+    fn new() -> Self {
+        const BLACK: Color = Color::new(0.0, 0.0, 0.0);
+        Self {
+            emitted: BLACK,
+            direct_diffuse: BLACK,
+            indirect_diffuse: BLACK,
+            direct_specular: BLACK,
+            indirect_specular: BLACK,
+            specular: false,
         }
+    }
 
-        // Apply gamma correction
-        color = Color {
-            r: (color.r / scene.settings.samples_per_pixel as Float).sqrt(),
-            g: (color.g / scene.settings.samples_per_pixel as Float).sqrt(),
-            b: (color.b / scene.settings.samples_per_pixel as Float).sqrt(),
+    /// Adds light that was scattered `bounces` times before reaching the camera
+    fn add(&mut self, bounces: u32, color: Color) {
+        let part = match (bounces, self.specular) {
+            (0, _) => &mut self.emitted,
+            (1, false) => &mut self.direct_diffuse,
+            (1, true) => &mut self.direct_specular,
+            (_, false) => &mut self.indirect_diffuse,
+            (_, true) => &mut self.indirect_specular,
         };
+        *part = *part + color;
+    }
 
-        // Write raw data to buffer
-        pixel.copy_from_slice(&color.into_raw());
+    fn total(&self) -> Color {
+        self.emitted
+            + self.direct_diffuse
+            + self.indirect_diffuse
+            + self.direct_specular
+            + self.indirect_specular
+    }
+}
+
+/// Everything needed to trace one pass of samples through a scene
+struct PassContext<'a> {
+    scene: &'a Scene,
+    world: &'a dyn Hittable,
+    lights: &'a LightList,
+    ray_origin: RayOrigin,
+    width: usize,
+    height: usize,
+    /// Indices of the samples of the pass for every pixel, used to seed the
+    /// sampler. Pixels without them are skipped
+    pending: &'a [Option<Range<u32>>],
+}
+
+impl PassContext<'_> {
+    fn tiles(&self) -> Vec<Tile> {
+        let settings = &self.scene.settings;
+        generate_tiles(
+            self.width,
+            self.height,
+            settings.tile_size,
+            settings.tile_order,
+            settings.region,
+        )
+    }
+
+    /// Traces the pending samples of every pixel of the tile. Once
+    /// `cancelled` is set the remaining pixels are skipped
+    fn trace_tile(&self, tile: &Tile, cancelled: &AtomicBool) -> Vec<PixelSamples> {
+        let settings = &self.scene.settings;
+        let mut pixels = Vec::with_capacity(tile.area());
+
+        for y in tile.y..tile.y + tile.height {
+            for x in tile.x..tile.x + tile.width {
+                let pixel = x + y * self.width;
+                let mut samples = PixelSamples {
+                    color: Color::new(0.0, 0.0, 0.0),
+                    luminance_squares: 0.0,
+                    samples: 0,
+                    aovs: None,
+                };
+
+                let pending = match &self.pending[pixel] {
+                    Some(pending) if !cancelled.load(Ordering::Relaxed) => pending.clone(),
+                    _ => {
+                        pixels.push(samples);
+                        continue;
+                    }
+                };
+
+                // Run for N samples
+                for sample in pending.clone() {
+                    let mut sampler = settings.sampler.sampler(SampleIndex {
+                        seed: settings.seed,
+                        pixel,
+                        sample,
+                        pass_sample: sample - pending.start,
+                        pass_samples: pending.len() as u32,
+                    });
+
+                    // UV coordinates, the pixel jitter is always the first dimension
+                    let (jitter_x, jitter_y) = sampler.next_2d();
+                    let u = (x as Float + jitter_x) / (self.width - 1) as Float;
+                    let v = (y as Float + jitter_y) / (self.height - 1) as Float;
+
+                    // Cast ray, the lens sample comes right after the jitter
+                    let ray = self.ray_origin.get_ray(u, v, &mut sampler);
+                    let mut aovs = AovSamples::new();
+                    let radiance = match (ray, settings.integrator) {
+                        // Outside of the image circle of a fisheye
+                        (None, _) => PathRadiance::new(),
+                        (Some(ray), Integrator::Path) => {
+                            self.trace_ray(&ray, &mut sampler, &mut aovs)
+                        }
+                        (Some(ray), Integrator::Mis) => {
+                            self.trace_mis(&ray, &mut sampler, &mut aovs)
+                        }
+                    };
+
+                    // Prevent fireflies, the parts of the radiance are scaled down
+                    // along with it
+                    let total = radiance.total();
+                    let clamp = settings.clamp_indirect;
+                    let ray_color = Color::new(
+                        total.r.clamp(0.0, clamp),
+                        total.g.clamp(0.0, clamp),
+                        total.b.clamp(0.0, clamp),
+                    );
+
+                    samples.color = samples.color + ray_color;
+                    samples.luminance_squares += ray_color.luminance().powi(2);
+
+                    if settings.aovs {
+                        let scale = Color::new(
+                            ray_color.r / total.r.max(Float::MIN_POSITIVE),
+                            ray_color.g / total.g.max(Float::MIN_POSITIVE),
+                            ray_color.b / total.b.max(Float::MIN_POSITIVE),
+                        );
+                        aovs.direct_diffuse = radiance.direct_diffuse * scale;
+                        aovs.indirect_diffuse = radiance.indirect_diffuse * scale;
+                        aovs.direct_specular = radiance.direct_specular * scale;
+                        aovs.indirect_specular = radiance.indirect_specular * scale;
+
+                        samples.aovs.get_or_insert_with(AovSamples::new).add(&aovs);
+                    }
+                }
+
+                samples.samples = pending.len() as u32;
+                pixels.push(samples);
+            }
+        }
+
+        pixels
+    }
+
+    /// Fills in the AOVs describing the first surface a camera ray hits
+    fn record_primary(
+        &self,
+        ray: &Ray,
+        hit: &HitRecord<MaterialHandle>,
+        material: Option<&dyn Material>,
+        aovs: &mut AovSamples,
+    ) {
+        if let Some(material) = material {
+            aovs.albedo = material.albedo(hit);
+        }
+        aovs.normal = hit.normal.normalize();
+        aovs.depth = hit.t * ray.direction.magnitude();
+        aovs.position = hit.point;
+        aovs.object = Some(hit.object);
+        aovs.material = Some(hit.material.0);
+        aovs.hits = 1;
+    }
+
+    /// Material used to shade a hit in the current render mode, or the color of
+    /// the hit for modes which don't trace any further
+    fn material(&self, hit: &HitRecord<MaterialHandle>) -> Result<&dyn Material, Color> {
+        match self.scene.settings.mode {
+            RenderMode::Full => Ok(&**self.scene.material(hit.material)),
+            RenderMode::Clay => Ok(&CLAY),
+            RenderMode::Normal => {
+                let normal = 0.5 * (hit.normal.normalize() + Vec3::new(1.0, 1.0, 1.0));
+                Err(Color::new(normal.x, normal.y, normal.z))
+            }
+            RenderMode::Random => Err(Color::new(0.0, 0.0, 0.0)),
+        }
+    }
+
+    /// Radiance arriving along a ray. Emission of lights is only counted if the
+    /// ray wasn't scattered in a non-specular direction, where they were already
+    /// sampled directly
+    fn trace_ray(
+        &self,
+        ray: &Ray,
+        sampler: &mut dyn Sampler,
+        aovs: &mut AovSamples,
+    ) -> PathRadiance {
+        let scene = self.scene;
+
+        // Clay scenes have no lights
+        let sample_lights = scene.settings.mode == RenderMode::Full;
+
+        let mut radiance = PathRadiance::new();
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let mut ray = *ray;
+        let mut count_emitted = true;
+
+        for depth in 0..scene.settings.max_ray_depth {
+            let hit = match self.world.hit(&ray, 0.00001, Float::INFINITY, sampler) {
+                Some(hit) => hit,
+                None => {
+                    if count_emitted || !self.lights.has_environment() {
+                        radiance.add(depth, throughput * scene.background.sample(&ray));
+                    }
+                    break;
+                }
+            };
+
+            let material = self.material(&hit);
+            if depth == 0 {
+                self.record_primary(&ray, &hit, material.ok(), aovs);
+            }
+
+            let material = match material {
+                Ok(material) => material,
+                Err(color) => {
+                    radiance.add(depth, throughput * color);
+                    return radiance;
+                }
+            };
+
+            if count_emitted || !self.lights.contains(hit.object) {
+                radiance.add(depth, throughput * material.emitted(&ray, &hit));
+            }
+
+            // Next event estimation, the shadow ray counts as a bounce so paths
+            // are no longer than without it
+            if sample_lights && depth + 1 < scene.settings.max_ray_depth {
+                let direct = self
+                    .lights
+                    .sample_direct(scene, self.world, &ray, &hit, material, sampler);
+                radiance.add(depth + 1, throughput * direct);
+            }
+
+            let sample = match material.sample(&ray, &hit, sampler) {
+                Some(sample) => sample,
+                None => break,
+            };
+
+            throughput = throughput * sample.weight;
+            if depth == 0 {
+                radiance.specular = sample.specular;
+            }
+            count_emitted = sample.specular || !sample_lights;
+            ray = Ray::with_time(hit.point, sample.direction, ray.time);
+
+            if !self.survives_roulette(depth, &mut throughput, sampler) {
+                break;
+            }
+        }
+
+        radiance
+    }
+
+    /// Russian roulette, past `roulette_depth` bounces paths are terminated with
+    /// a probability based on their throughput. Surviving paths are reweighted to
+    /// keep the estimate unbiased
+    fn survives_roulette(
+        &self,
+        depth: u32,
+        throughput: &mut Color,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        if depth + 1 < self.scene.settings.roulette_depth {
+            return true;
+        }
+
+        let probability = throughput.max_component().min(1.0);
+        if probability <= 0.0 || sampler.next_1d() >= probability {
+            return false;
+        }
+
+        *throughput = *throughput * (1.0 / probability);
+        true
+    }
+
+    /// Radiance arriving along a ray, every emitter found is weighted against the
+    /// chance of having sampled it directly from the previous hit
+    fn trace_mis(
+        &self,
+        ray: &Ray,
+        sampler: &mut dyn Sampler,
+        aovs: &mut AovSamples,
+    ) -> PathRadiance {
+        let scene = self.scene;
+        let sample_lights = scene.settings.mode == RenderMode::Full;
+
+        let mut radiance = PathRadiance::new();
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let mut ray = *ray;
+
+        // Camera rays count as specular, nothing could have sampled them
+        let mut specular = true;
+        let mut bsdf_pdf = 0.0;
+
+        for depth in 0..scene.settings.max_ray_depth {
+            let hit = match self.world.hit(&ray, 0.00001, Float::INFINITY, sampler) {
+                Some(hit) => hit,
+                None => {
+                    let weight = if specular || !sample_lights {
+                        1.0
+                    } else {
+                        let light_pdf = self.lights.environment_pdf(scene, ray.direction);
+                        power_heuristic(bsdf_pdf, light_pdf)
+                    };
+
+                    radiance.add(depth, throughput * scene.background.sample(&ray) * weight);
+                    break;
+                }
+            };
+
+            let material = self.material(&hit);
+            if depth == 0 {
+                self.record_primary(&ray, &hit, material.ok(), aovs);
+            }
+
+            let material = match material {
+                Ok(material) => material,
+                Err(color) => {
+                    radiance.add(depth, throughput * color);
+                    return radiance;
+                }
+            };
+
+            let emitted = material.emitted(&ray, &hit);
+            if !emitted.is_black() {
+                let weight = if specular || !sample_lights {
+                    1.0
+                } else {
+                    let light_pdf = self.lights.pdf(scene, &ray, &hit);
+                    power_heuristic(bsdf_pdf, light_pdf)
+                };
+
+                radiance.add(depth, throughput * emitted * weight);
+            }
+
+            // The shadow ray counts as a bounce
+            if sample_lights && depth + 1 < scene.settings.max_ray_depth {
+                let direct = self
+                    .lights
+                    .sample_direct_mis(scene, self.world, &ray, &hit, material, sampler);
+                radiance.add(depth + 1, throughput * direct);
+            }
+
+            let sample = match material.sample(&ray, &hit, sampler) {
+                Some(sample) => sample,
+                None => break,
+            };
+
+            throughput = throughput * sample.weight;
+            if depth == 0 {
+                radiance.specular = sample.specular;
+            }
+            specular = sample.specular;
+            bsdf_pdf = sample.pdf;
+            ray = Ray::with_time(hit.point, sample.direction, ray.time);
+
+            if !self.survives_roulette(depth, &mut throughput, sampler) {
+                break;
+            }
+        }
+
+        radiance
+    }
+}
+
+/// Scene together with the nodes of a Bvh tree built earlier, this way the
+/// scene lock can be released between tiles without rebuilding the tree
+struct LockedBvh<'a> {
+    scene: &'a Scene,
+    bvh: &'a LinearBvh,
+}
+
+impl Hittable for LockedBvh<'_> {
+    fn hit(
+        &self,
+        ray: &Ray,
+        tmin: Float,
+        tmax: Float,
+        sampler: &mut dyn Sampler,
+    ) -> Option<HitRecord<MaterialHandle>> {
+        self.bvh.hit(ray, tmin, tmax, self.scene, sampler)
+    }
+}
+
+/// Handle to a render running on a background thread. The job keeps adding
+/// passes of `samples_per_pixel` samples to the shared target until it holds
+/// the requested number of samples, or the job is cancelled. Tiles are added
+/// to the target as soon as they are finished
+///
+/// The scene is only read locked while a tile is traced, so it can be edited
+/// while the job runs. Any change to the scene should be followed by cancelling
+/// the job and starting a new one on a cleared target
+pub struct RenderJob {
+    cancelled: Arc<AtomicBool>,
+    started: Instant,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl RenderJob {
+    pub fn spawn(
+        scene: Arc<RwLock<Scene>>,
+        target: Arc<Mutex<RenderTarget>>,
+        total_samples: u32,
+    ) -> Self {
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let cancelled = Arc::clone(&cancelled);
+            std::thread::spawn(move || run_job(&scene, &target, total_samples, &cancelled))
+        };
+
+        Self {
+            cancelled,
+            started: Instant::now(),
+            handle: Some(handle),
+        }
+    }
+
+    /// Asks the job to stop, the pass being rendered is left partly
+    /// accumulated. Tiles finished before this call stay in the target, but
+    /// the pass isn't counted in its samples. Returns immediately, the worker
+    /// thread exits shortly after
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Cancels the job and waits for the worker thread to exit
+    pub fn stop(mut self) {
+        self.cancel();
+        self.join();
+    }
+
+    /// Blocks until the job is done
+    pub fn wait(mut self) {
+        self.join();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle
+            .as_ref()
+            .is_none_or(|handle| handle.is_finished())
+    }
+
+    /// Time since the job was started
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn join(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.join().expect("render thread panicked");
+        }
+    }
+}
+
+impl Drop for RenderJob {
+    fn drop(&mut self) {
+        self.cancel();
+        self.join();
+    }
+}
+
+fn run_job(
+    scene: &RwLock<Scene>,
+    target: &Mutex<RenderTarget>,
+    total_samples: u32,
+    cancelled: &AtomicBool,
+) {
+    let (width, height) = {
+        let target = target.lock().unwrap();
+        (target.width, target.height)
     };
 
-    // Use parallel iterators to automatically create thread pool
-    if scene.settings.enable_multithreading {
-        target
-            .data
-            .par_chunks_exact_mut(4)
-            .enumerate()
-            .for_each(calculate_pixel);
-    } else {
-        target
-            .data
-            .chunks_exact_mut(4)
-            .enumerate()
-            .for_each(calculate_pixel);
+    // Objects don't change while the job runs, so the tree and the light list
+    // are only built once
+    let (bvh, lights) = {
+        let scene = scene.read().unwrap();
+        (scene.bvh(), LightList::build(&scene))
+    };
+
+    loop {
+        let done = target.lock().unwrap().samples;
+        if done >= total_samples || cancelled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let (tiles, samples, multithreaded, pending) = {
+            let scene = scene.read().unwrap();
+            let settings = &scene.settings;
+
+            let samples = settings.samples_per_pixel.min(total_samples - done).max(1);
+            let mut target = target.lock().unwrap();
+            let pending = target.pending_samples(settings, samples);
+            if pending.iter().all(Option::is_none) {
+                target.converged = true;
+                return;
+            }
+
+            let tiles = generate_tiles(
+                width,
+                height,
+                settings.tile_size,
+                settings.tile_order,
+                settings.region,
+            );
+            (tiles, samples, settings.enable_multithreading, pending)
+        };
+
+        for_each_tile(&tiles, multithreaded, |tile| {
+            let scene = scene.read().unwrap();
+            if cancelled.load(Ordering::Relaxed) {
+                return;
+            }
+
+            let bvh = LockedBvh {
+                scene: &scene,
+                bvh: &bvh,
+            };
+            let world: &dyn Hittable = if scene.settings.enable_bvh_tree {
+                &bvh
+            } else {
+                &*scene
+            };
+
+            let context = PassContext {
+                scene: &scene,
+                world,
+                lights: &lights,
+                ray_origin: scene.camera().ray_origin(),
+                width,
+                height,
+                pending: &pending,
+            };
+
+            let pixels = context.trace_tile(tile, cancelled);
+            drop(scene);
+
+            if !cancelled.load(Ordering::Relaxed) {
+                target.lock().unwrap().add_tile(tile, &pixels);
+            }
+        });
+
+        if cancelled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        target.lock().unwrap().finish_pass(samples);
     }
 }
@@ -1,16 +1,38 @@
-use crate::utils::{aabb::AABB, ray::Ray, types::*};
+use crate::utils::{
+    aabb::AABB,
+    ray::Ray,
+    sample::{Sampler, SamplerKind},
+    types::*,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
 
 use crate::{
-    backgrounds::UniformBackground, core::camera::Camera, utils::color::Color,
+    backgrounds::UniformBackground,
+    core::camera::{Camera, Projection},
+    utils::color::Color,
     utils::ray::HitRecord,
 };
 
 use super::{
-    bvh::{BoundsCollection, BvhTree},
+    bvh::{BoundsCollection, BvhSettings, BvhTree, LinearBvh, LinearBvhTree},
+    tiles::{Region, TileOrder},
     traits::{Background, Hittable, Material, Object},
 };
 
+/// Algorithm used to estimate the light arriving along camera rays
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Integrator {
+    /// Path tracer which samples the lights directly at every non-specular hit
+    /// and ignores emission found by the bounce after it
+    #[serde(alias = "Recursive")]
+    Path,
+    /// Iterative path tracer combining bsdf and light sampling with multiple
+    /// importance sampling
+    Mis,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum RenderMode {
     Full,
@@ -19,24 +41,116 @@ pub enum RenderMode {
     Normal,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RenderSettings {
     pub samples_per_pixel: u32,
-    pub max_ray_depth: u8,
+    /// Stop sampling pixels once their noise falls below `noise_threshold`.
+    /// Passes keep their cost, the samples of converged pixels go to the noisy
+    /// ones, which stop at `max_samples`
+    pub adaptive_sampling: bool,
+    /// Samples every pixel gets before adaptive sampling may stop it
+    pub min_samples: u32,
+    /// Adaptive sampling never takes more samples than this for a pixel
+    pub max_samples: u32,
+    /// Standard error of the displayed brightness of a pixel, relative to
+    /// white, below which it counts as converged
+    pub noise_threshold: Float,
+    /// Paths are cut off after this many bounces
+    pub max_ray_depth: u32,
+    /// Bounces after which paths are randomly terminated based on their throughput
+    pub roulette_depth: u32,
     pub clamp_indirect: f32,
     pub enable_multithreading: bool,
     pub enable_bvh_tree: bool,
+    pub bvh: BvhSettings,
     pub mode: RenderMode,
+    pub integrator: Integrator,
+    /// Renders with the same seed are identical
+    pub seed: u64,
+    /// Where the random numbers of every sample come from
+    pub sampler: SamplerKind,
+    /// Also render the AOV buffers
+    pub aovs: bool,
+    /// Side length of the square tiles the image is split into
+    pub tile_size: usize,
+    pub tile_order: TileOrder,
+    /// Only render this part of the image
+    pub region: Option<Region>,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            samples_per_pixel: 5,
+            adaptive_sampling: false,
+            min_samples: 16,
+            max_samples: 1024,
+            noise_threshold: 0.01,
+            max_ray_depth: 6,
+            roulette_depth: 3,
+            clamp_indirect: 10.0,
+            enable_multithreading: true,
+            enable_bvh_tree: true,
+            bvh: BvhSettings::default(),
+            mode: RenderMode::Full,
+            integrator: Integrator::Path,
+            seed: 0,
+            sampler: SamplerKind::Independent,
+            aovs: false,
+            tile_size: 32,
+            tile_order: TileOrder::Spiral,
+            region: None,
+        }
+    }
+}
+
+/// What has to happen to the cached Bvh tree before it can be used again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum BvhState {
+    Valid,
+    /// Objects moved, the bounds of the nodes are out of date
+    Refit,
+    /// Objects were added or removed
+    Rebuild,
+}
+
+/// Bvh tree over the objects of a scene, kept between renders
+struct BvhCache {
+    bvh: Arc<LinearBvh>,
+    /// Settings the tree was built with
+    settings: BvhSettings,
+    state: BvhState,
+}
+
+impl Default for BvhCache {
+    fn default() -> Self {
+        Self {
+            bvh: Arc::default(),
+            settings: BvhSettings::default(),
+            state: BvhState::Rebuild,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Scene {
+    /// Camera rendered from unless another one is active
     pub camera: Camera,
+    /// More viewpoints on the scene by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cameras: BTreeMap<String, Camera>,
+    /// Name of the camera to render from, the default camera if it is None or
+    /// not found
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_camera: Option<String>,
     pub settings: RenderSettings,
     pub background: Box<dyn Background>,
-    // Temporary
-    pub objects: Vec<Box<dyn Object>>,
+    // Changed through the methods below, which keep the Bvh tree up to date
+    pub(crate) objects: Vec<Box<dyn Object>>,
     materials: Vec<Box<dyn Material>>,
+    #[serde(skip)]
+    bvh: Mutex<BvhCache>,
 }
 
 impl Default for Scene {
@@ -48,18 +162,24 @@ impl Default for Scene {
                 vertical: Vec3::new(0.0, 1.0, 0.0),
                 vertical_fov: 90.0,
                 aspect_ratio: 1.0,
+                aperture: 0.0,
+                focus_distance: 1.0,
+                autofocus: true,
+                blades: 0,
+                blade_rotation: 0.0,
+                projection: Projection::Perspective,
+                shutter_open: 0.0,
+                shutter_close: 0.0,
+                track: None,
+                stereo: None,
             },
-            settings: RenderSettings {
-                samples_per_pixel: 5,
-                max_ray_depth: 6,
-                clamp_indirect: 10.0,
-                enable_multithreading: true,
-                enable_bvh_tree: true,
-                mode: RenderMode::Full,
-            },
+            cameras: BTreeMap::new(),
+            active_camera: None,
+            settings: RenderSettings::default(),
             background: Box::new(UniformBackground::new(Color::new(0.8, 0.8, 0.8))),
             objects: Vec::new(),
             materials: Vec::new(),
+            bvh: Mutex::default(),
         }
     }
 }
@@ -72,31 +192,73 @@ pub struct ObjectHandle(pub usize);
 
 impl Scene {
     pub fn from_file(path: &str) -> Self {
-        serde_json::from_str(&std::fs::read_to_string(path).unwrap()[..]).unwrap()
+        Self::load(path).unwrap()
+    }
+
+    /// Loads a scene from a json file, reporting any io or parsing errors
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents[..])?)
     }
 
     pub fn new(settings: RenderSettings, camera: Camera, background: Box<dyn Background>) -> Self {
         Self {
             camera,
+            cameras: BTreeMap::new(),
+            active_camera: None,
             settings,
             background,
             objects: Vec::new(),
             materials: Vec::new(),
+            bvh: Mutex::default(),
+        }
+    }
+
+    /// The camera to render from
+    pub fn camera(&self) -> &Camera {
+        self.active_camera
+            .as_ref()
+            .and_then(|name| self.cameras.get(name))
+            .unwrap_or(&self.camera)
+    }
+
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        match self
+            .active_camera
+            .as_ref()
+            .and_then(|name| self.cameras.get_mut(name))
+        {
+            Some(camera) => camera,
+            None => &mut self.camera,
         }
     }
 
     pub fn add_object(&mut self, object: Box<dyn Object>) -> ObjectHandle {
         self.objects.push(object);
+        self.invalidate_bvh();
         ObjectHandle(self.objects.len() - 1)
     }
 
+    /// Removes an object, the handles of the objects after it move down by one
+    pub fn remove_object(&mut self, object: ObjectHandle) -> Box<dyn Object> {
+        self.invalidate_bvh();
+        self.objects.remove(object.0)
+    }
+
     #[inline]
     pub fn object(&self, object: ObjectHandle) -> &Box<dyn Object> {
         // SAFETY: Shouln't be out of bounds because ObjectHandle only constructed
-        // in this impl as an index in a grow-only vector
+        // in this impl as an index in the vector
         &self.objects[object.0]
     }
 
+    /// Object for moving it around, the Bvh tree is refit to its new bounds
+    /// before the next render
+    pub fn object_mut(&mut self, object: ObjectHandle) -> &mut Box<dyn Object> {
+        self.mark_bvh(BvhState::Refit);
+        &mut self.objects[object.0]
+    }
+
     pub fn add_material(&mut self, material: Box<dyn Material>) -> MaterialHandle {
         self.materials.push(material);
         MaterialHandle(self.materials.len() - 1)
@@ -109,8 +271,39 @@ impl Scene {
         &self.materials[material.0]
     }
 
-    pub fn build_bvh(&self) -> BvhTree<Scene> {
-        BvhTree::build(&self)
+    pub fn build_bvh(&self) -> LinearBvhTree<Scene> {
+        LinearBvhTree::flatten(BvhTree::build(self, &self.settings.bvh))
+    }
+
+    /// Bvh tree over the objects, which is only rebuilt or refit when they
+    /// changed since the last call
+    pub fn bvh(&self) -> Arc<LinearBvh> {
+        let mut cache = self.bvh.lock().unwrap();
+        if cache.settings != self.settings.bvh {
+            cache.state = BvhState::Rebuild;
+        }
+
+        match cache.state {
+            BvhState::Valid => {}
+            BvhState::Refit => Arc::make_mut(&mut cache.bvh).refit(self),
+            BvhState::Rebuild => {
+                cache.bvh = Arc::new(self.build_bvh().into_bvh());
+                cache.settings = self.settings.bvh;
+            }
+        }
+
+        cache.state = BvhState::Valid;
+        cache.bvh.clone()
+    }
+
+    /// Rebuilds the Bvh tree before the next render
+    pub fn invalidate_bvh(&mut self) {
+        self.mark_bvh(BvhState::Rebuild);
+    }
+
+    fn mark_bvh(&mut self, state: BvhState) {
+        let cache = self.bvh.get_mut().unwrap();
+        cache.state = cache.state.max(state);
     }
 }
 
@@ -133,20 +326,30 @@ impl BoundsCollection for Scene {
         ray: &Ray,
         tmin: Float,
         tmax: Float,
+        sampler: &mut dyn Sampler,
     ) -> Option<HitRecord<MaterialHandle>> {
-        self.objects[handle as usize].hit(ray, tmin, tmax)
+        let mut hit = self.objects[handle as usize].hit(ray, tmin, tmax, sampler)?;
+        hit.object = handle as usize;
+        Some(hit)
     }
 }
 
 impl Hittable for Scene {
-    fn hit(&self, ray: &Ray, tmin: Float, tmax: Float) -> Option<HitRecord<MaterialHandle>> {
+    fn hit(
+        &self,
+        ray: &Ray,
+        tmin: Float,
+        tmax: Float,
+        sampler: &mut dyn Sampler,
+    ) -> Option<HitRecord<MaterialHandle>> {
         let mut result = None;
         let mut closest_so_far = tmax;
 
-        for object in &self.objects {
+        for (index, object) in self.objects.iter().enumerate() {
             //if object.bounds().hit(ray, tmin, tmax) {
-            if let Some(hit) = object.hit(ray, tmin, closest_so_far) {
+            if let Some(mut hit) = object.hit(ray, tmin, closest_so_far, sampler) {
                 closest_so_far = hit.t;
+                hit.object = index;
                 result = Some(hit);
             }
             //}
@@ -155,3 +358,79 @@ impl Hittable for Scene {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        materials::Dielectric,
+        objects::{Instance, Sphere},
+        utils::{
+            sample::SampleIndex,
+            transform::{Motion, Transform},
+        },
+    };
+
+    /// Closest hits of rays through the scene along every axis, by distance
+    fn hits(scene: &Scene, bvh: &LinearBvh) -> Vec<Option<Float>> {
+        let mut sampler = SamplerKind::Independent.sampler(SampleIndex {
+            seed: 0,
+            pixel: 0,
+            sample: 0,
+            pass_sample: 0,
+            pass_samples: 1,
+        });
+
+        let mut hits = Vec::new();
+        for a in -40..=40 {
+            for b in -40..=40 {
+                let (a, b) = (a as Float * 0.25, b as Float * 0.25);
+                for (origin, direction) in [
+                    (Vec3::new(-50.0, a, b), Vec3::unit_x()),
+                    (Vec3::new(a, -50.0, b), Vec3::unit_y()),
+                    (Vec3::new(a, b, -50.0), Vec3::unit_z()),
+                ] {
+                    let ray = Ray::new(origin, direction);
+                    let hit = bvh.hit(&ray, 0.001, Float::INFINITY, scene, &mut sampler);
+                    hits.push(hit.map(|hit| hit.t));
+                }
+            }
+        }
+        hits
+    }
+
+    #[test]
+    fn moved_objects_refit_bvh() {
+        let mut scene = Scene::default();
+        let material = scene.add_material(Box::new(Dielectric::new(1.5)));
+        for x in -3..=3 {
+            for y in -3..=3 {
+                let center = Vec3::new(x as Float, y as Float, 0.0) * 1.5;
+                scene.add_object(Box::new(Sphere::new(center, 0.5, material)));
+            }
+        }
+        let sphere = Box::new(Sphere::new(Vec3::new(0.0, 0.0, 0.0), 0.5, material));
+        let instance = scene.add_object(Box::new(Instance::new(
+            sphere,
+            Motion::Static(Transform::default()),
+        )));
+
+        let before = scene.bvh();
+        assert_eq!(
+            hits(&scene, &before),
+            hits(&scene, &scene.build_bvh().into_bvh())
+        );
+
+        // Out of the grid, so the old bounds would miss it
+        let moved = Transform {
+            translation: Vec3::new(7.0, -6.0, 4.0),
+            ..Transform::default()
+        };
+        *scene.object_mut(instance).motion_mut().unwrap() = Motion::Static(moved);
+
+        let refit = scene.bvh();
+        let rebuilt = scene.build_bvh().into_bvh();
+        assert_eq!(hits(&scene, &refit), hits(&scene, &rebuilt));
+        assert_ne!(hits(&scene, &before), hits(&scene, &refit));
+    }
+}
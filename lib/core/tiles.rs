@@ -0,0 +1,188 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Order in which the tiles of an image are handed out to the render threads
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TileOrder {
+    /// Left to right, top to bottom
+    Scanline,
+    /// Outwards from the center of the image
+    Spiral,
+    /// Along a Hilbert curve, neighbouring tiles are rendered close in time
+    Hilbert,
+}
+
+/// Rectangular block of pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Region {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Number of pixels in the region
+    pub fn area(&self) -> usize {
+        self.width * self.height
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+
+    /// Overlapping part of two regions, if any
+    pub fn intersect(&self, other: &Region) -> Option<Region> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+
+        if right > x && bottom > y {
+            Some(Region::new(x, y, right - x, bottom - y))
+        } else {
+            None
+        }
+    }
+}
+
+/// A single unit of render work
+pub type Tile = Region;
+
+/// Reported every time a tile of a pass is finished
+#[derive(Debug, Clone, Copy)]
+pub struct TileReport {
+    pub tile: Tile,
+    /// Time it took to trace the tile
+    pub duration: Duration,
+    /// Number of tiles of the pass finished so far, including this one
+    pub finished: usize,
+    /// Number of tiles in the pass
+    pub total: usize,
+}
+
+/// Split an image into tiles of at most `tile_size` pixels a side, restricted
+/// to the region of interest if there is one
+pub fn generate_tiles(
+    width: usize,
+    height: usize,
+    tile_size: usize,
+    order: TileOrder,
+    region: Option<Region>,
+) -> Vec<Tile> {
+    let image = Region::new(0, 0, width, height);
+    let region = match region {
+        Some(region) => match region.intersect(&image) {
+            Some(region) => region,
+            None => return Vec::new(),
+        },
+        None => image,
+    };
+
+    let tile_size = tile_size.max(1);
+    let columns = region.width.div_ceil(tile_size);
+    let rows = region.height.div_ceil(tile_size);
+
+    let cells = match order {
+        TileOrder::Scanline => scanline_order(columns, rows),
+        TileOrder::Spiral => spiral_order(columns, rows),
+        TileOrder::Hilbert => hilbert_order(columns, rows),
+    };
+
+    cells
+        .into_iter()
+        .filter_map(|(column, row)| {
+            let tile = Region::new(
+                region.x + column * tile_size,
+                region.y + row * tile_size,
+                tile_size,
+                tile_size,
+            );
+            tile.intersect(&region)
+        })
+        .collect()
+}
+
+fn scanline_order(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .collect()
+}
+
+/// Walks a square spiral around the center cell, skipping cells outside the grid
+fn spiral_order(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let total = columns * rows;
+    let mut cells = Vec::with_capacity(total);
+
+    let mut x = (columns as isize - 1) / 2;
+    let mut y = (rows as isize - 1) / 2;
+    let (mut dx, mut dy) = (1, 0);
+    let mut leg_length = 1;
+
+    let visit = |x: isize, y: isize, cells: &mut Vec<(usize, usize)>| {
+        if x >= 0 && y >= 0 && (x as usize) < columns && (y as usize) < rows {
+            cells.push((x as usize, y as usize));
+        }
+    };
+
+    visit(x, y, &mut cells);
+    while cells.len() < total {
+        // Every two legs the spiral grows by one cell
+        for _ in 0..2 {
+            for _ in 0..leg_length {
+                x += dx;
+                y += dy;
+                visit(x, y, &mut cells);
+            }
+            (dx, dy) = (-dy, dx);
+        }
+        leg_length += 1;
+    }
+
+    cells
+}
+
+/// Sorts the cells by their distance along a Hilbert curve covering the grid
+fn hilbert_order(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let side = columns.max(rows).next_power_of_two();
+
+    let mut cells = scanline_order(columns, rows);
+    cells.sort_by_key(|&(x, y)| hilbert_index(side, x, y));
+    cells
+}
+
+/// Position of a cell along the Hilbert curve of a `side` by `side` grid
+fn hilbert_index(side: usize, x: usize, y: usize) -> usize {
+    let (mut x, mut y) = (x, y);
+    let mut index = 0;
+
+    let mut s = side / 2;
+    while s > 0 {
+        let rx = usize::from(x & s > 0);
+        let ry = usize::from(y & s > 0);
+        index += s * s * ((3 * rx) ^ ry);
+
+        // Rotate the quadrant so the curve stays continuous
+        if ry == 0 {
+            if rx == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        s /= 2;
+    }
+
+    index
+}
@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{color::Color, types::*};
+
+/// Curves compressing linear radiance into the displayable range
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ToneMapper {
+    /// Clips everything above white
+    Clamp,
+    /// `x / (1 + x)` per channel
+    Reinhard,
+    /// Stephen Hill's fit of the ACES reference and output transforms
+    Filmic,
+    /// Troy Sobotka's AgX, desaturates highlights instead of skewing their hue
+    AgX,
+}
+
+/// Turns the linear radiance of a render into display ready sRGB
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToneMapping {
+    /// Brightness adjustment in stops, every stop doubles the radiance
+    pub exposure: Float,
+    pub mapper: ToneMapper,
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            mapper: ToneMapper::Clamp,
+        }
+    }
+}
+
+impl ToneMapping {
+    /// Maps linear radiance to sRGB encoded channels in [0, 1]
+    pub fn apply(&self, color: Color) -> Color {
+        let color = color * self.exposure.exp2();
+        let mapped = match self.mapper {
+            ToneMapper::Clamp => color,
+            ToneMapper::Reinhard => map_channels(color, |x| x / (1.0 + x)),
+            ToneMapper::Filmic => filmic(color),
+            ToneMapper::AgX => agx(color),
+        };
+
+        map_channels(mapped, |x| srgb_encode(x.clamp(0.0, 1.0)))
+    }
+}
+
+fn map_channels<F: Fn(Float) -> Float>(color: Color, f: F) -> Color {
+    Color::new(f(color.r), f(color.g), f(color.b))
+}
+
+/// Multiplies a color with a row major 3x3 matrix
+fn transform(matrix: &[[Float; 3]; 3], color: Color) -> Color {
+    let [r, g, b] = color.data();
+    Color::from(matrix.map(|row| row[0] * r + row[1] * g + row[2] * b))
+}
+
+/// The sRGB transfer function, linear near black and a 2.4 power above
+pub fn srgb_encode(x: Float) -> Float {
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn filmic(color: Color) -> Color {
+    // Linear sRGB to the ACES working space, including the reference look
+    const INPUT: [[Float; 3]; 3] = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    const OUTPUT: [[Float; 3]; 3] = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+
+    let color = map_channels(transform(&INPUT, color), |x| {
+        (x * (x + 0.0245786) - 0.000090537) / (x * (0.983729 * x + 0.432951) + 0.238081)
+    });
+    transform(&OUTPUT, color)
+}
+
+fn agx(color: Color) -> Color {
+    const INSET: [[Float; 3]; 3] = [
+        [0.8424791, 0.0784336, 0.07922375],
+        [0.04232824, 0.8784686, 0.07916613],
+        [0.04237565, 0.0784336, 0.879143],
+    ];
+    const OUTSET: [[Float; 3]; 3] = [
+        [1.196879, -0.09802088, -0.09902974],
+        [-0.05289685, 1.151903, -0.09896118],
+        [-0.05297164, -0.09804345, 1.151074],
+    ];
+    // Range of the log encoding in stops around middle grey
+    const MIN_EV: Float = -12.47393;
+    const MAX_EV: Float = 4.026069;
+
+    let color = map_channels(transform(&INSET, color), |x| {
+        let x = (x.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+
+        // Polynomial fit of the default AgX contrast curve
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    });
+
+    // The curve produces display encoded values, undo the 2.2 gamma
+    map_channels(transform(&OUTSET, color), |x| x.max(0.0).powf(2.2))
+}
@@ -3,28 +3,122 @@ use crate::{
     utils::{
         aabb::Bounded,
         color::Color,
-        ray::{HitRecord, Ray},
-        types::Float,
+        ray::{HitRecord, Ray, ScatterSample},
+        sample::Sampler,
+        transform::Motion,
+        types::{Float, Vec3},
     },
 };
 
+use super::light::LightSample;
 use super::scene::MaterialHandle;
 
 pub trait Hittable: Sync {
-    fn hit(&self, ray: &Ray, tmin: Float, tmax: Float) -> Option<HitRecord<MaterialHandle>>;
+    fn hit(
+        &self,
+        ray: &Ray,
+        tmin: Float,
+        tmax: Float,
+        sampler: &mut dyn Sampler,
+    ) -> Option<HitRecord<MaterialHandle>>;
 }
 
 #[typetag::serde(tag = "type")]
-pub trait Object: Sync + Hittable + Bounded {
+pub trait Object: Send + Sync + Hittable + Bounded {
     fn material(&self) -> MaterialHandle;
+
+    /// Whether `sample_light` picks points on the object, only emissive objects
+    /// which can be sampled are added to the lights of the scene
+    fn samples_light(&self) -> bool {
+        false
+    }
+
+    /// Picks a point on the surface to sample light from, as seen from `origin`
+    /// at `time`
+    fn sample_light(
+        &self,
+        _origin: Vec3,
+        _time: Float,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<LightSample> {
+        None
+    }
+
+    /// Probability density with respect to solid angle of `sample_light` picking
+    /// the point of `hit`, as seen from `origin` at `time`
+    fn light_pdf(&self, _origin: Vec3, _time: Float, _hit: &HitRecord<MaterialHandle>) -> Float {
+        0.0
+    }
+
+    /// Transform placing the object over time, None if it is placed by its
+    /// own geometry. Go through `Scene::object_mut` to change it, so the Bvh
+    /// tree is refit
+    fn motion_mut(&mut self) -> Option<&mut Motion> {
+        None
+    }
 }
 
+/// Surface or volume scattering model. Directions passed in and returned all
+/// point away from the hit point, the outgoing direction is `-ray.direction`
 #[typetag::serde(tag = "type")]
-pub trait Material: Sync {
-    fn scatter(&self, ray: &Ray, hit: &HitRecord<MaterialHandle>) -> (Color, Option<Ray>);
+pub trait Material: Send + Sync {
+    /// Bsdf times the cosine term for light arriving from `direction`, zero for
+    /// perfectly specular materials
+    fn eval(&self, _ray: &Ray, _hit: &HitRecord<MaterialHandle>, _direction: Vec3) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    /// Picks a direction to continue the path in, None if the path is absorbed
+    fn sample(
+        &self,
+        ray: &Ray,
+        hit: &HitRecord<MaterialHandle>,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterSample>;
+
+    /// Probability density with respect to solid angle of `sample` picking
+    /// `direction`, zero for perfectly specular materials
+    fn pdf(&self, _ray: &Ray, _hit: &HitRecord<MaterialHandle>, _direction: Vec3) -> Float {
+        0.0
+    }
+
+    /// Fraction of light the surface reflects, written to the albedo AOV
+    fn albedo(&self, _hit: &HitRecord<MaterialHandle>) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    /// Light emitted from the hit point back along the ray
+    fn emitted(&self, _ray: &Ray, _hit: &HitRecord<MaterialHandle>) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    /// Objects with an emissive material are sampled directly as lights
+    fn is_emissive(&self) -> bool {
+        false
+    }
 }
 
 #[typetag::serde(tag = "type")]
-pub trait Background: Sync + Editable {
+pub trait Background: Send + Sync + Editable {
     fn sample(&self, ray: &Ray) -> Color;
+
+    /// Copy of the background, keeping what was cached for sampling it
+    fn box_clone(&self) -> Box<dyn Background>;
+
+    /// Backgrounds which are lights get sampled directly like emissive objects
+    fn is_light(&self) -> bool {
+        false
+    }
+
+    /// Picks a unit direction to sample light from, together with its
+    /// probability density with respect to solid angle
+    fn sample_direction(&self, _sampler: &mut dyn Sampler) -> Option<(Vec3, Float)> {
+        None
+    }
+
+    /// Probability density with respect to solid angle of `sample_direction`
+    /// picking `direction`
+    fn pdf(&self, _direction: Vec3) -> Float {
+        0.0
+    }
 }
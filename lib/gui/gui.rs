@@ -1,6 +1,6 @@
 use crate::{
     backgrounds::{GradientBackground, SkyMap, UniformBackground},
-    core::camera::Camera,
+    core::camera::{Camera, Projection, Stereo, StereoLayout},
     utils::color::Color,
     utils::types::Vec3,
 };
@@ -86,6 +86,142 @@ impl Editable for Camera {
             *modified |= ui
                 .add(egui::Slider::new(&mut self.vertical_fov, 0.60..=120.0))
                 .changed();
+
+            ui.horizontal(|ui| {
+                ui.label("Projection:");
+                let name = |projection: &Projection| match projection {
+                    Projection::Perspective => "Perspective",
+                    Projection::Orthographic { .. } => "Orthographic",
+                    Projection::Equirectangular => "Equirectangular",
+                    Projection::Fisheye { .. } => "Fisheye",
+                    Projection::Cylindrical { .. } => "Cylindrical",
+                };
+                egui::ComboBox::from_id_source("projection")
+                    .selected_text(name(&self.projection))
+                    .show_ui(ui, |ui| {
+                        for projection in [
+                            Projection::Perspective,
+                            Projection::Orthographic { height: 2.0 },
+                            Projection::Equirectangular,
+                            Projection::Fisheye { fov: 180.0 },
+                            Projection::Cylindrical { fov: 360.0 },
+                        ] {
+                            let selected = name(&self.projection) == name(&projection);
+                            if ui.selectable_label(selected, name(&projection)).clicked()
+                                && !selected
+                            {
+                                self.projection = projection;
+                                *modified = true;
+                            }
+                        }
+                    });
+            });
+            match &mut self.projection {
+                Projection::Orthographic { height } => {
+                    ui.label("View height:");
+                    *modified |= ui
+                        .add(egui::Slider::new(height, 0.01..=100.0).logarithmic(true))
+                        .changed();
+                }
+                Projection::Fisheye { fov } => {
+                    ui.label("Image circle field of view:");
+                    *modified |= ui.add(egui::Slider::new(fov, 1.0..=360.0)).changed();
+                }
+                Projection::Cylindrical { fov } => {
+                    ui.label("Horizontal field of view:");
+                    *modified |= ui.add(egui::Slider::new(fov, 1.0..=360.0)).changed();
+                }
+                Projection::Perspective | Projection::Equirectangular => {}
+            }
+
+            ui.label("Aperture:");
+            *modified |= ui
+                .add(egui::Slider::new(&mut self.aperture, 0.0..=1.0).logarithmic(true))
+                .changed();
+            *modified |= ui
+                .checkbox(&mut self.autofocus, "Focus on look at")
+                .changed();
+            if self.autofocus {
+                // Show where the camera focuses, to start from when switching off
+                self.focus_distance = self.focus_distance();
+            }
+            ui.label("Focus distance:");
+            *modified |= ui
+                .add_enabled(
+                    !self.autofocus,
+                    egui::Slider::new(&mut self.focus_distance, 0.01..=100.0).logarithmic(true),
+                )
+                .changed();
+            ui.label("Aperture blades:");
+            *modified |= ui
+                .add(egui::Slider::new(&mut self.blades, 0..=12))
+                .changed();
+            ui.label("Blade rotation:");
+            *modified |= ui
+                .add(egui::Slider::new(&mut self.blade_rotation, 0.0..=360.0).suffix("°"))
+                .changed();
+
+            ui.label("Shutter:");
+            ui.horizontal(|ui| {
+                *modified |= ui
+                    .add(
+                        egui::widgets::DragValue::new(&mut self.shutter_open)
+                            .speed(0.01)
+                            .prefix("open: "),
+                    )
+                    .changed();
+                *modified |= ui
+                    .add(
+                        egui::widgets::DragValue::new(&mut self.shutter_close)
+                            .speed(0.01)
+                            .prefix("close: "),
+                    )
+                    .changed();
+            });
+
+            let mut stereo = self.stereo.is_some();
+            if ui.checkbox(&mut stereo, "Stereo").changed() {
+                self.stereo = stereo.then_some(Stereo {
+                    interaxial: 0.065,
+                    convergence: None,
+                    layout: StereoLayout::SideBySide,
+                });
+                *modified = true;
+            }
+            if let Some(stereo) = &mut self.stereo {
+                ui.horizontal(|ui| {
+                    *modified |= ui
+                        .selectable_value(
+                            &mut stereo.layout,
+                            StereoLayout::SideBySide,
+                            "Side by side",
+                        )
+                        .changed();
+                    *modified |= ui
+                        .selectable_value(&mut stereo.layout, StereoLayout::TopBottom, "Top bottom")
+                        .changed();
+                });
+                ui.label("Interaxial distance:");
+                *modified |= ui
+                    .add(egui::Slider::new(&mut stereo.interaxial, 0.0..=1.0).logarithmic(true))
+                    .changed();
+
+                // Converging at the focus distance is the default
+                let mut at_focus = stereo.convergence.is_none();
+                if ui
+                    .checkbox(&mut at_focus, "Converge at the focus distance")
+                    .changed()
+                {
+                    stereo.convergence = (!at_focus).then_some(self.focus_distance);
+                    *modified = true;
+                }
+                if let Some(distance) = &mut stereo.convergence {
+                    ui.label("Convergence distance:");
+                    *modified |= ui
+                        .add(egui::Slider::new(distance, 0.01..=100.0).logarithmic(true))
+                        .changed();
+                }
+            }
         })
     }
 }
@@ -3,14 +3,13 @@ use crate::{
     utils::{
         color::Color,
         math::{near_zero, reflect, refract},
-        ray::Ray,
-        sample::sample_unit_sphere_surface,
-        types::Float,
+        ray::{Ray, ScatterSample},
+        sample::{sample_unit_sphere_surface, Sampler},
+        types::{Float, Vec3, PI},
     },
 };
 use cgmath::InnerSpace;
 use derive_new::new;
-use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
 use crate::utils::ray::HitRecord;
@@ -21,23 +20,46 @@ pub struct Lambertian {
 }
 
 impl Lambertian {
-    pub fn new(albedo: Color) -> Self {
+    pub const fn new(albedo: Color) -> Self {
         Self { albedo }
     }
 }
 
 #[typetag::serde]
 impl Material for Lambertian {
-    fn scatter(&self, _ray: &Ray, hit: &HitRecord<MaterialHandle>) -> (Color, Option<Ray>) {
-        let mut scatter_direction = hit.normal + sample_unit_sphere_surface();
+    fn albedo(&self, _hit: &HitRecord<MaterialHandle>) -> Color {
+        self.albedo
+    }
+
+    fn eval(&self, _ray: &Ray, hit: &HitRecord<MaterialHandle>, direction: Vec3) -> Color {
+        self.albedo * (hit.normal.dot(direction).max(0.0) / PI)
+    }
+
+    fn sample(
+        &self,
+        _ray: &Ray,
+        hit: &HitRecord<MaterialHandle>,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterSample> {
+        // Cosine weighted, so the weight is just the albedo
+        let mut scatter_direction = hit.normal + sample_unit_sphere_surface(sampler.next_2d());
 
         if near_zero(scatter_direction) {
             // Catch degenerate scatter direction
             scatter_direction = hit.normal;
         }
 
-        let scattered = Ray::new(hit.point, scatter_direction);
-        (self.albedo, Some(scattered))
+        let direction = scatter_direction.normalize();
+        Some(ScatterSample {
+            direction,
+            weight: self.albedo,
+            pdf: hit.normal.dot(direction).max(0.0) / PI,
+            specular: false,
+        })
+    }
+
+    fn pdf(&self, _ray: &Ray, hit: &HitRecord<MaterialHandle>, direction: Vec3) -> Float {
+        hit.normal.dot(direction).max(0.0) / PI
     }
 }
 
@@ -55,17 +77,30 @@ impl Metal {
 
 #[typetag::serde]
 impl Material for Metal {
-    fn scatter(&self, ray: &Ray, hit: &HitRecord<MaterialHandle>) -> (Color, Option<Ray>) {
+    fn albedo(&self, _hit: &HitRecord<MaterialHandle>) -> Color {
+        self.albedo
+    }
+
+    /// Fuzzy reflections have no closed form density, so they are treated as
+    /// specular
+    fn sample(
+        &self,
+        ray: &Ray,
+        hit: &HitRecord<MaterialHandle>,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterSample> {
         let reflected = reflect(ray.direction, hit.normal).normalize();
-        let scattered = Ray::new(
-            hit.point,
-            reflected + self.fuzz * sample_unit_sphere_surface(),
-        );
+        let direction = reflected + self.fuzz * sample_unit_sphere_surface(sampler.next_2d());
 
-        if scattered.direction.dot(hit.normal) > 0.0 {
-            (self.albedo, Some(scattered))
+        if direction.dot(hit.normal) > 0.0 {
+            Some(ScatterSample {
+                direction: direction.normalize(),
+                weight: self.albedo,
+                pdf: 0.0,
+                specular: true,
+            })
         } else {
-            (Color::new(0.0, 0.0, 0.0), None)
+            None
         }
     }
 }
@@ -85,8 +120,21 @@ impl Emission {
 
 #[typetag::serde]
 impl Material for Emission {
-    fn scatter(&self, _ray: &Ray, _hit: &HitRecord<MaterialHandle>) -> (Color, Option<Ray>) {
-        (self.color, None)
+    fn sample(
+        &self,
+        _ray: &Ray,
+        _hit: &HitRecord<MaterialHandle>,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<ScatterSample> {
+        None
+    }
+
+    fn emitted(&self, _ray: &Ray, _hit: &HitRecord<MaterialHandle>) -> Color {
+        self.color
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
     }
 }
 
@@ -110,7 +158,16 @@ impl Dielectric {
 
 #[typetag::serde]
 impl Material for Dielectric {
-    fn scatter(&self, ray: &Ray, hit: &HitRecord<MaterialHandle>) -> (Color, Option<Ray>) {
+    fn albedo(&self, _hit: &HitRecord<MaterialHandle>) -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
+
+    fn sample(
+        &self,
+        ray: &Ray,
+        hit: &HitRecord<MaterialHandle>,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterSample> {
         let refraction_ratio = if hit.front_face {
             1.0 / self.ir
         } else {
@@ -123,18 +180,19 @@ impl Material for Dielectric {
 
         let cannot_refract: bool = (refraction_ratio * sin_theta) > 1.0;
         let direction = if cannot_refract
-            || Dielectric::reflectance(cos_theta, refraction_ratio)
-                > thread_rng().gen_range(0.0..1.0)
+            || Dielectric::reflectance(cos_theta, refraction_ratio) > sampler.next_1d()
         {
             reflect(unit_direction, hit.normal)
         } else {
             refract(unit_direction, hit.normal, refraction_ratio)
         };
 
-        (
-            Color::new(1.0, 1.0, 1.0),
-            Some(Ray::new(hit.point, direction)),
-        )
+        Some(ScatterSample {
+            direction: direction.normalize(),
+            weight: Color::new(1.0, 1.0, 1.0),
+            pdf: 0.0,
+            specular: true,
+        })
     }
 }
 
@@ -147,11 +205,56 @@ pub struct MixMaterial {
 
 #[typetag::serde]
 impl Material for MixMaterial {
-    fn scatter(&self, ray: &Ray, hit: &HitRecord<MaterialHandle>) -> (Color, Option<Ray>) {
-        if thread_rng().gen_range(0.0..1.0) >= self.factor {
-            self.first.scatter(ray, hit)
+    fn eval(&self, ray: &Ray, hit: &HitRecord<MaterialHandle>, direction: Vec3) -> Color {
+        self.first.eval(ray, hit, direction) * (1.0 - self.factor)
+            + self.second.eval(ray, hit, direction) * self.factor
+    }
+
+    fn sample(
+        &self,
+        ray: &Ray,
+        hit: &HitRecord<MaterialHandle>,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterSample> {
+        let sample = if sampler.next_1d() >= self.factor {
+            self.first.sample(ray, hit, sampler)?
         } else {
-            self.second.scatter(ray, hit)
+            self.second.sample(ray, hit, sampler)?
+        };
+
+        if sample.specular {
+            // The probability of picking the material cancels out with its factor
+            return Some(sample);
         }
+
+        // Either material could have picked this direction
+        let pdf = self.pdf(ray, hit, sample.direction);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some(ScatterSample {
+            weight: self.eval(ray, hit, sample.direction) * (1.0 / pdf),
+            pdf,
+            ..sample
+        })
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &HitRecord<MaterialHandle>, direction: Vec3) -> Float {
+        self.first.pdf(ray, hit, direction) * (1.0 - self.factor)
+            + self.second.pdf(ray, hit, direction) * self.factor
+    }
+
+    fn albedo(&self, hit: &HitRecord<MaterialHandle>) -> Color {
+        self.first.albedo(hit) * (1.0 - self.factor) + self.second.albedo(hit) * self.factor
+    }
+
+    fn emitted(&self, ray: &Ray, hit: &HitRecord<MaterialHandle>) -> Color {
+        self.first.emitted(ray, hit) * (1.0 - self.factor)
+            + self.second.emitted(ray, hit) * self.factor
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.first.is_emissive() || self.second.is_emissive()
     }
 }
@@ -1,10 +1,13 @@
 use crate::{
+    core::light::LightSample,
     core::scene::MaterialHandle,
     core::traits::{Hittable, Object},
     utils::ray::HitRecord,
     utils::{
         aabb::{Bounded, AABB},
         ray::Ray,
+        sample::{sample_cone, sample_unit_sphere_surface, Sampler},
+        transform::Motion,
         types::*,
     },
 };
@@ -19,8 +22,26 @@ pub struct Sphere {
     material: MaterialHandle,
 }
 
+impl Sphere {
+    /// Uniform density over the cone of directions the sphere covers, seen from
+    /// a point outside it. 1 - cos_theta_max is computed without cancellation
+    /// for small or distant spheres
+    fn cone_pdf(&self, distance2: Float) -> Float {
+        let sin2_theta_max = self.radius * self.radius / distance2;
+        let cos_theta_max = (1.0 - sin2_theta_max).sqrt();
+        let cone_width = sin2_theta_max / (1.0 + cos_theta_max);
+        1.0 / (2.0 * PI * cone_width)
+    }
+}
+
 impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, tmin: Float, tmax: Float) -> Option<HitRecord<MaterialHandle>> {
+    fn hit(
+        &self,
+        ray: &Ray,
+        tmin: Float,
+        tmax: Float,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<HitRecord<MaterialHandle>> {
         let oc = ray.origin - self.center;
 
         let a = ray.direction.magnitude2();
@@ -64,4 +85,158 @@ impl Object for Sphere {
     fn material(&self) -> MaterialHandle {
         self.material
     }
+
+    fn samples_light(&self) -> bool {
+        true
+    }
+
+    fn sample_light(
+        &self,
+        origin: Vec3,
+        _time: Float,
+        sampler: &mut dyn Sampler,
+    ) -> Option<LightSample> {
+        let to_center = self.center - origin;
+        let distance2 = to_center.magnitude2();
+        let radius2 = self.radius * self.radius;
+
+        if distance2 <= radius2 {
+            // Inside the sphere every point is visible, sample the whole surface
+            let normal = sample_unit_sphere_surface(sampler.next_2d());
+            let point = self.center + normal * self.radius;
+            let to_point = point - origin;
+            let cosine = normal.dot(to_point.normalize()).abs();
+            let area = 4.0 * PI * radius2;
+
+            return Some(LightSample {
+                point,
+                normal,
+                pdf: to_point.magnitude2() / (cosine * area),
+            });
+        }
+
+        // Sample the cone of directions the sphere covers
+        let cos_theta_max = (1.0 - radius2 / distance2).sqrt();
+        let direction = sample_cone(
+            to_center / distance2.sqrt(),
+            cos_theta_max,
+            sampler.next_2d(),
+        );
+        let hit = self.hit(&Ray::new(origin, direction), 0.0, Float::INFINITY, sampler)?;
+
+        Some(LightSample {
+            point: hit.point,
+            normal: (hit.point - self.center) / self.radius,
+            pdf: self.cone_pdf(distance2),
+        })
+    }
+
+    fn light_pdf(&self, origin: Vec3, _time: Float, hit: &HitRecord<MaterialHandle>) -> Float {
+        let distance2 = (self.center - origin).magnitude2();
+        let radius2 = self.radius * self.radius;
+
+        if distance2 <= radius2 {
+            let to_point = hit.point - origin;
+            let cosine = hit.normal.dot(to_point.normalize()).abs();
+            to_point.magnitude2() / (cosine * 4.0 * PI * radius2)
+        } else {
+            self.cone_pdf(distance2)
+        }
+    }
+}
+
+/// Another object placed in the scene by a transform which may change over
+/// time, moving objects are blurred over the camera shutter
+#[derive(Serialize, Deserialize)]
+pub struct Instance {
+    object: Box<dyn Object>,
+    motion: Motion,
+}
+
+impl Instance {
+    pub fn new(object: Box<dyn Object>, motion: Motion) -> Self {
+        Self { object, motion }
+    }
+
+    pub fn motion(&self) -> &Motion {
+        &self.motion
+    }
+
+    pub fn set_motion(&mut self, motion: Motion) {
+        self.motion = motion;
+    }
+}
+
+impl Hittable for Instance {
+    fn hit(
+        &self,
+        ray: &Ray,
+        tmin: Float,
+        tmax: Float,
+        sampler: &mut dyn Sampler,
+    ) -> Option<HitRecord<MaterialHandle>> {
+        // The direction keeps its scale, so distances along the ray are the
+        // same in both spaces
+        let transform = self.motion.at(ray.time);
+        let local_ray = Ray::with_time(
+            transform.inverse_point(ray.origin),
+            transform.inverse_vector(ray.direction),
+            ray.time,
+        );
+
+        let mut hit = self.object.hit(&local_ray, tmin, tmax, sampler)?;
+        hit.point = transform.point(hit.point);
+        hit.normal = transform.normal(hit.normal).normalize();
+        Some(hit)
+    }
+}
+
+impl Bounded for Instance {
+    fn bounds(&self) -> AABB {
+        self.motion.bounds(self.object.bounds())
+    }
+}
+
+#[typetag::serde]
+impl Object for Instance {
+    fn material(&self) -> MaterialHandle {
+        self.object.material()
+    }
+
+    fn samples_light(&self) -> bool {
+        self.object.samples_light()
+    }
+
+    // Angles are kept by the transform, so are densities with respect to
+    // solid angle
+    fn sample_light(
+        &self,
+        origin: Vec3,
+        time: Float,
+        sampler: &mut dyn Sampler,
+    ) -> Option<LightSample> {
+        let transform = self.motion.at(time);
+        let sample = self
+            .object
+            .sample_light(transform.inverse_point(origin), time, sampler)?;
+
+        Some(LightSample {
+            point: transform.point(sample.point),
+            normal: transform.normal(sample.normal).normalize(),
+            pdf: sample.pdf,
+        })
+    }
+
+    fn light_pdf(&self, origin: Vec3, time: Float, hit: &HitRecord<MaterialHandle>) -> Float {
+        let transform = self.motion.at(time);
+        let mut local_hit = *hit;
+        local_hit.point = transform.inverse_point(hit.point);
+        local_hit.normal = transform.inverse_normal(hit.normal);
+
+        self.object
+            .light_pdf(transform.inverse_point(origin), time, &local_hit)
+    }
+    fn motion_mut(&mut self) -> Option<&mut Motion> {
+        Some(&mut self.motion)
+    }
 }
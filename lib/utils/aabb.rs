@@ -37,6 +37,13 @@ impl AABB {
         )
     }
 
+    pub fn surface_area(&self) -> Float {
+        let dimensions = self.dimensions();
+        2.0 * (dimensions.x * dimensions.y
+            + dimensions.y * dimensions.z
+            + dimensions.z * dimensions.x)
+    }
+
     /// Returns a bounding box surrouding two bounding boxes
     pub fn surround(first: Self, second: Self) -> Self {
         Self {
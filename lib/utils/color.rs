@@ -12,7 +12,7 @@ pub struct Color {
 
 impl Color {
     #[inline]
-    pub fn new(r: Float, g: Float, b: Float) -> Self {
+    pub const fn new(r: Float, g: Float, b: Float) -> Self {
         Self { r, g, b }
     }
 
@@ -30,14 +30,29 @@ impl Color {
         [self.r, self.g, self.b]
     }
 
+    /// Relative luminance of linear Rec. 709 primaries
+    #[inline]
+    pub fn luminance(&self) -> Float {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    #[inline]
+    pub fn max_component(&self) -> Float {
+        self.r.max(self.g).max(self.b)
+    }
+
+    /// Returns true if all channels are zero
+    #[inline]
+    pub fn is_black(&self) -> bool {
+        self.r == 0.0 && self.g == 0.0 && self.b == 0.0
+    }
+
+    /// Quantizes channels in [0, 1] to opaque 8-bit RGBA, values outside the
+    /// range are clamped
     #[inline]
     pub fn into_raw(&self) -> [u8; 4] {
-        [
-            (self.r * 255.0) as u8,
-            (self.g * 255.0) as u8,
-            (self.b * 255.0) as u8,
-            255,
-        ]
+        let quantize = |x: Float| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+        [quantize(self.r), quantize(self.g), quantize(self.b), 255]
     }
 }
 
@@ -37,3 +37,28 @@ pub fn degrees_to_radians(degrees: Float) -> Float {
 pub fn radians_to_degrees(radians: Float) -> Float {
     radians * 180.0 / PI
 }
+
+/// Multiple importance sampling weight of a sample taken with density `pdf`,
+/// when the same direction could also have been picked with `other_pdf`
+#[inline]
+pub fn power_heuristic(pdf: Float, other_pdf: Float) -> Float {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+/// Two unit vectors which together with `normal` form an orthonormal basis
+pub fn orthonormal_basis(normal: Vec3) -> (Vec3, Vec3) {
+    let helper = if normal.x.abs() > 0.9 {
+        Vec3::new(0.0, 1.0, 0.0)
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    };
+
+    let tangent = normal.cross(helper).normalize();
+    let bitangent = normal.cross(tangent);
+    (tangent, bitangent)
+}
@@ -3,5 +3,6 @@ pub mod color;
 pub mod math;
 pub mod ray;
 pub mod sample;
+pub mod transform;
 pub mod types;
 pub mod vector;
@@ -3,15 +3,29 @@ use std::ops::{Add, Mul};
 use cgmath::InnerSpace;
 use derive_new::new;
 
-use super::types::{Float, Vec3};
+use super::{
+    color::Color,
+    types::{Float, Vec3},
+};
 
 #[derive(new, Clone, Copy)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
+    /// Point in time within the camera shutter the ray was sent at
+    #[new(default)]
+    pub time: Float,
 }
 
 impl Ray {
+    pub fn with_time(origin: Vec3, direction: Vec3, time: Float) -> Self {
+        Self {
+            origin,
+            direction,
+            time,
+        }
+    }
+
     pub fn at(&self, t: Float) -> Vec3 {
         self.origin + self.direction * t
     }
@@ -26,6 +40,21 @@ impl Ray {
     }
 }
 
+/// Direction picked by a material to continue a path in
+#[derive(Clone, Copy)]
+pub struct ScatterSample {
+    /// Unit length direction away from the hit point
+    pub direction: Vec3,
+    /// Bsdf times cosine divided by the pdf, the factor the radiance arriving
+    /// from `direction` is multiplied with
+    pub weight: Color,
+    /// Probability density with respect to solid angle, zero for specular samples
+    pub pdf: Float,
+    /// True if the direction was picked from a delta distribution, like a
+    /// mirror reflection, which `Material::eval` doesn't account for
+    pub specular: bool,
+}
+
 #[derive(Clone, Copy)]
 pub struct HitRecord<M> {
     pub point: Vec3,
@@ -33,6 +62,9 @@ pub struct HitRecord<M> {
     pub t: Float,
     pub front_face: bool,
     pub material: M,
+    /// Index of the hit object in the scene, filled in by the scene once an
+    /// object reports a hit
+    pub object: usize,
 }
 
 impl<M> HitRecord<M> {
@@ -50,6 +82,7 @@ impl<M> HitRecord<M> {
             t,
             front_face,
             material,
+            object: 0,
         }
     }
 }
@@ -2,9 +2,373 @@ use std::ops::RangeInclusive;
 
 use cgmath::InnerSpace;
 use derive_new::new;
-use rand::{distributions::uniform::SampleRange, thread_rng, Rng};
+use rand::{distributions::uniform::SampleRange, Rng};
+use rand_pcg::Pcg32;
+use serde::{Deserialize, Serialize};
 
-use super::{aabb::AABB, types::*};
+use super::{aabb::AABB, math::orthonormal_basis, types::*};
+
+/// Source of the uniform numbers used to sample a single path. Every call
+/// advances to the next dimension, so draws for the same purpose line up across
+/// the samples of a pixel and low-discrepancy samplers can spread them out
+pub trait Sampler {
+    /// Uniform number in [0, 1)
+    fn next_1d(&mut self) -> Float;
+
+    /// Pair of uniform numbers in [0, 1), well distributed as a point in 2D
+    fn next_2d(&mut self) -> (Float, Float);
+}
+
+/// Samplers which can be picked in the render settings
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SamplerKind {
+    /// Independent random numbers
+    Independent,
+    /// Jittered strata, shuffled per dimension
+    Stratified,
+    /// Halton sequence, randomly shifted per pixel
+    Halton,
+    /// Owen-scrambled Sobol sequence
+    Sobol,
+}
+
+impl SamplerKind {
+    /// Sampler for one sample of one pixel
+    pub fn sampler(self, index: SampleIndex) -> PixelSampler {
+        match self {
+            SamplerKind::Independent => PixelSampler::Independent(IndependentSampler::new(index)),
+            SamplerKind::Stratified => PixelSampler::Stratified(StratifiedSampler::new(index)),
+            SamplerKind::Halton => PixelSampler::Halton(HaltonSampler::new(index)),
+            SamplerKind::Sobol => PixelSampler::Sobol(SobolSampler::new(index)),
+        }
+    }
+}
+
+/// Any of the samplers, so one can be picked per sample without allocating
+pub enum PixelSampler {
+    Independent(IndependentSampler),
+    Stratified(StratifiedSampler),
+    Halton(HaltonSampler),
+    Sobol(SobolSampler),
+}
+
+impl Sampler for PixelSampler {
+    fn next_1d(&mut self) -> Float {
+        match self {
+            PixelSampler::Independent(sampler) => sampler.next_1d(),
+            PixelSampler::Stratified(sampler) => sampler.next_1d(),
+            PixelSampler::Halton(sampler) => sampler.next_1d(),
+            PixelSampler::Sobol(sampler) => sampler.next_1d(),
+        }
+    }
+
+    fn next_2d(&mut self) -> (Float, Float) {
+        match self {
+            PixelSampler::Independent(sampler) => sampler.next_2d(),
+            PixelSampler::Stratified(sampler) => sampler.next_2d(),
+            PixelSampler::Halton(sampler) => sampler.next_2d(),
+            PixelSampler::Sobol(sampler) => sampler.next_2d(),
+        }
+    }
+}
+
+/// Identifies one sample of one pixel
+#[derive(Debug, Clone, Copy)]
+pub struct SampleIndex {
+    pub seed: u64,
+    pub pixel: usize,
+    /// Index of the sample within the pixel
+    pub sample: u32,
+    /// Index of the sample within its pass
+    pub pass_sample: u32,
+    /// Number of samples taken in the pass
+    pub pass_samples: u32,
+}
+
+impl SampleIndex {
+    /// Hash identifying the pixel, shared by all of its samples
+    fn pixel_hash(&self) -> u64 {
+        splitmix64(self.seed ^ splitmix64(self.pixel as u64))
+    }
+
+    /// Hash identifying this sample of this pixel
+    fn sample_hash(&self) -> u64 {
+        splitmix64(self.pixel_hash() ^ self.sample as u64)
+    }
+}
+
+/// Scrambles the bits of a 64-bit integer
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Largest float below one, so draws never reach the end of the interval
+const ONE_MINUS_EPSILON: Float = 1.0 - Float::EPSILON / 2.0;
+
+#[inline]
+fn to_unit(bits: u32) -> Float {
+    (bits as Float * (1.0 / 4_294_967_296.0)).min(ONE_MINUS_EPSILON)
+}
+
+/// Independent uniform numbers, every sample of every pixel gets its own stream
+pub struct IndependentSampler {
+    rng: Pcg32,
+}
+
+impl IndependentSampler {
+    pub fn new(index: SampleIndex) -> Self {
+        Self {
+            rng: Pcg32::new(index.sample_hash(), index.pixel_hash()),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn next_1d(&mut self) -> Float {
+        self.rng.gen_range(0.0..1.0)
+    }
+
+    fn next_2d(&mut self) -> (Float, Float) {
+        (self.next_1d(), self.next_1d())
+    }
+}
+
+/// Splits every dimension into one stratum per sample of the pass and jitters
+/// within it. The strata are shuffled independently per dimension, 2D draws are
+/// Latin hypercube samples
+pub struct StratifiedSampler {
+    index: SampleIndex,
+    dimension: u32,
+    rng: Pcg32,
+}
+
+impl StratifiedSampler {
+    pub fn new(index: SampleIndex) -> Self {
+        Self {
+            index,
+            dimension: 0,
+            rng: Pcg32::new(index.sample_hash(), index.pixel_hash()),
+        }
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn next_1d(&mut self) -> Float {
+        let seed = splitmix64(self.index.pixel_hash() ^ self.dimension as u64);
+        self.dimension += 1;
+
+        // Every pass is stratified on its own, so it needs its own shuffle
+        let first_sample = self.index.sample - self.index.pass_sample;
+        let shuffle = splitmix64(seed ^ first_sample as u64) as u32;
+        let stratum = permute(self.index.pass_sample, self.index.pass_samples, shuffle);
+        let jitter: Float = self.rng.gen_range(0.0..1.0);
+        ((stratum as Float + jitter) / self.index.pass_samples as Float).min(ONE_MINUS_EPSILON)
+    }
+
+    fn next_2d(&mut self) -> (Float, Float) {
+        (self.next_1d(), self.next_1d())
+    }
+}
+
+/// Random permutation of [0, length) picked by `seed`, evaluated for a single
+/// element without storing it. Kensler, "Correlated Multi-Jittered Sampling"
+fn permute(mut index: u32, length: u32, seed: u32) -> u32 {
+    let mut mask = length.wrapping_sub(1);
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+
+    // Cycle walk until the index lands inside the range
+    loop {
+        index ^= seed;
+        index = index.wrapping_mul(0xe170_893d);
+        index ^= seed >> 16;
+        index ^= (index & mask) >> 4;
+        index ^= seed >> 8;
+        index = index.wrapping_mul(0x0929_eb3f);
+        index ^= seed >> 23;
+        index ^= (index & mask) >> 1;
+        index = index.wrapping_mul(1 | seed >> 27);
+        index = index.wrapping_mul(0x6935_fa69);
+        index ^= (index & mask) >> 11;
+        index = index.wrapping_mul(0x74dc_b303);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0x9e50_1cc3);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0xc860_a3df);
+        index &= mask;
+        index ^= index >> 5;
+
+        if index < length {
+            return index.wrapping_add(seed) % length;
+        }
+    }
+}
+
+/// Bases of the Halton sequence, one prime per dimension
+const HALTON_PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+/// Halton sequence over the samples of a pixel, randomly shifted per pixel and
+/// dimension so neighbouring pixels don't share the same pattern. Dimensions
+/// past the last prime fall back to independent numbers
+pub struct HaltonSampler {
+    index: SampleIndex,
+    dimension: usize,
+    rng: Pcg32,
+}
+
+impl HaltonSampler {
+    pub fn new(index: SampleIndex) -> Self {
+        Self {
+            index,
+            dimension: 0,
+            rng: Pcg32::new(index.sample_hash(), index.pixel_hash()),
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn next_1d(&mut self) -> Float {
+        let dimension = self.dimension;
+        self.dimension += 1;
+
+        match HALTON_PRIMES.get(dimension) {
+            Some(&base) => {
+                let shift = splitmix64(self.index.pixel_hash() ^ dimension as u64) as u32;
+                let value = radical_inverse(self.index.sample, base) + to_unit(shift) as f64;
+                (value.fract() as Float).min(ONE_MINUS_EPSILON)
+            }
+            None => self.rng.gen_range(0.0..1.0),
+        }
+    }
+
+    fn next_2d(&mut self) -> (Float, Float) {
+        (self.next_1d(), self.next_1d())
+    }
+}
+
+/// Mirrors the digits of `index` in `base` around the decimal point
+fn radical_inverse(mut index: u32, base: u32) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut scale = inverse_base;
+    let mut value = 0.0;
+
+    while index > 0 {
+        value += (index % base) as f64 * scale;
+        index /= base;
+        scale *= inverse_base;
+    }
+
+    value
+}
+
+/// Owen-scrambled Sobol sequence over the samples of a pixel. Every draw uses
+/// the first one or two Sobol dimensions with its own scramble and shuffle, as
+/// in Burley, "Practical Hash-based Owen Scrambling"
+pub struct SobolSampler {
+    index: SampleIndex,
+    dimension: u64,
+}
+
+impl SobolSampler {
+    pub fn new(index: SampleIndex) -> Self {
+        Self {
+            index,
+            dimension: 0,
+        }
+    }
+
+    /// Seed of the next draw, and the shuffled sample index used for it
+    fn next_draw(&mut self) -> (u32, u32) {
+        let seed = splitmix64(self.index.pixel_hash() ^ self.dimension) as u32;
+        self.dimension += 1;
+
+        let index = nested_uniform_scramble(self.index.sample, seed);
+        (seed, index)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn next_1d(&mut self) -> Float {
+        let (seed, index) = self.next_draw();
+        to_unit(nested_uniform_scramble(
+            sobol_first(index),
+            hash_u32(seed, 0),
+        ))
+    }
+
+    fn next_2d(&mut self) -> (Float, Float) {
+        let (seed, index) = self.next_draw();
+        (
+            to_unit(nested_uniform_scramble(
+                sobol_first(index),
+                hash_u32(seed, 0),
+            )),
+            to_unit(nested_uniform_scramble(
+                sobol_second(index),
+                hash_u32(seed, 1),
+            )),
+        )
+    }
+}
+
+/// First Sobol dimension, the van der Corput sequence in base 2
+#[inline]
+fn sobol_first(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+/// Second Sobol dimension, its generator matrix is the Pascal matrix mod 2
+fn sobol_second(mut index: u32) -> u32 {
+    let mut result = 0;
+    let mut direction: u32 = 1 << 31;
+
+    while index > 0 {
+        if index & 1 == 1 {
+            result ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+
+    result
+}
+
+/// Owen scramble of the bits of `value`, from the most significant one down
+fn nested_uniform_scramble(value: u32, seed: u32) -> u32 {
+    laine_karras_permutation(value.reverse_bits(), seed).reverse_bits()
+}
+
+/// Hash where every bit only depends on the bits below it
+fn laine_karras_permutation(mut value: u32, seed: u32) -> u32 {
+    value = value.wrapping_add(seed);
+    value ^= value.wrapping_mul(0x6c50_b47c);
+    value ^= value.wrapping_mul(0xb82f_1e52);
+    value ^= value.wrapping_mul(0xc7af_e638);
+    value ^= value.wrapping_mul(0x8d22_f6e6);
+    value
+}
+
+#[inline]
+fn hash_u32(seed: u32, value: u32) -> u32 {
+    splitmix64(((seed as u64) << 32) | value as u64) as u32
+}
+
+/// Uniform direction, mapped from a point in the unit square
+pub fn sample_unit_sphere_surface(u: (Float, Float)) -> Vec3 {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
 
 /// Uniformly samples vectors in a axis aligned cube region
 #[derive(new, Clone, Copy)]
@@ -56,8 +420,8 @@ impl SampleRange<Vec3> for UnitSphereVolumeSampler {
     }
 }
 
-pub fn sample_unit_sphere_volume() -> Vec3 {
-    UnitSphereVolumeSampler::default().sample_single(&mut thread_rng())
+pub fn sample_unit_sphere_volume<R: Rng + ?Sized>(rng: &mut R) -> Vec3 {
+    UnitSphereVolumeSampler::default().sample_single(rng)
 }
 
 #[derive(Default)]
@@ -74,6 +438,156 @@ impl SampleRange<Vec3> for UnitSphereSurfaceSampler {
     }
 }
 
-pub fn sample_unit_sphere_surface() -> Vec3 {
-    UnitSphereSurfaceSampler::default().sample_single(&mut thread_rng())
+/// Uniformly samples a direction inside the cone around the unit vector `axis`
+/// with the half angle whose cosine is `cos_theta_max`
+pub fn sample_cone(axis: Vec3, cos_theta_max: Float, u: (Float, Float)) -> Vec3 {
+    let cos_theta = 1.0 - u.0 * (1.0 - cos_theta_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+
+    let (tangent, bitangent) = orthonormal_basis(axis);
+    (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta + axis * cos_theta
+}
+
+/// Uniformly samples barycentric coordinates `(u, v)` of a point in a triangle
+pub fn sample_triangle(u: (Float, Float)) -> (Float, Float) {
+    let sqrt_r1 = u.0.sqrt();
+    (sqrt_r1 * (1.0 - u.1), sqrt_r1 * u.1)
+}
+
+/// Uniform point in the unit disk, using Shirley's concentric mapping so
+/// strata of the square stay compact on the disk
+pub fn sample_unit_disk(u: (Float, Float)) -> (Float, Float) {
+    let x = 2.0 * u.0 - 1.0;
+    let y = 2.0 * u.1 - 1.0;
+    if x == 0.0 && y == 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let (r, theta) = if x.abs() > y.abs() {
+        (x, PI / 4.0 * (y / x))
+    } else {
+        (y, PI / 2.0 - PI / 4.0 * (x / y))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+/// Uniform point in the regular polygon inscribed in the unit circle, with its
+/// first corner `rotation` radians from the x axis
+pub fn sample_regular_polygon(sides: u32, rotation: Float, u: (Float, Float)) -> (Float, Float) {
+    // The polygon is a fan of equal triangles around the center, the first
+    // draw picks one and is reused within it
+    let scaled = u.0 * sides as Float;
+    let side = (scaled as u32).min(sides - 1);
+    let (b1, b2) = sample_triangle((scaled - side as Float, u.1));
+
+    let angle = 2.0 * PI / sides as Float;
+    let first = rotation + side as Float * angle;
+    let second = first + angle;
+    (
+        b1 * first.cos() + b2 * second.cos(),
+        b1 * first.sin() + b2 * second.sin(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 4] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+    ];
+
+    fn index(pixel: usize, sample: u32, pass_samples: u32) -> SampleIndex {
+        SampleIndex {
+            seed: 7,
+            pixel,
+            sample,
+            pass_sample: sample % pass_samples,
+            pass_samples,
+        }
+    }
+
+    /// Draws of one sample, alternating between 1D and 2D ones
+    fn draws(kind: SamplerKind, index: SampleIndex, count: usize) -> Vec<Float> {
+        let mut sampler = kind.sampler(index);
+        let mut draws = Vec::new();
+        for dimension in 0..count {
+            if dimension % 2 == 0 {
+                draws.push(sampler.next_1d());
+            } else {
+                let (u, v) = sampler.next_2d();
+                draws.extend([u, v]);
+            }
+        }
+        draws
+    }
+
+    #[test]
+    fn draws_are_in_unit_interval() {
+        for kind in KINDS {
+            for pixel in 0..16 {
+                for sample in 0..64 {
+                    // Past the last Halton prime too
+                    for draw in draws(kind, index(pixel, sample, 16), 48) {
+                        assert!((0.0..1.0).contains(&draw), "{:?} drew {}", kind, draw);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn draws_are_deterministic() {
+        for kind in KINDS {
+            for pixel in 0..4 {
+                for sample in 0..8 {
+                    let index = index(pixel, sample, 8);
+                    assert_eq!(draws(kind, index, 16), draws(kind, index, 16), "{:?}", kind);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sobol_draws_fill_elementary_intervals() {
+        for pixel in 0..8 {
+            for k in 0..=8 {
+                let count = 1u32 << k;
+                let mut points_1d = Vec::new();
+                let mut points_2d = Vec::new();
+                for sample in 0..count {
+                    let mut sampler = SamplerKind::Sobol.sampler(index(pixel, sample, count));
+                    points_1d.push(sampler.next_1d());
+                    points_2d.push(sampler.next_2d());
+                }
+
+                let cell = |value: Float, cells: u32| (value * cells as Float) as u32;
+
+                let mut hits = vec![0; count as usize];
+                for &u in &points_1d {
+                    hits[cell(u, count) as usize] += 1;
+                }
+                assert!(hits.iter().all(|&hits| hits == 1), "1D, 2^{}", k);
+
+                // Every split of the 2^k cells into columns and rows
+                for columns in 0..=k {
+                    let (columns, rows) = (1 << columns, 1 << (k - columns));
+                    let mut hits = vec![0; count as usize];
+                    for &(u, v) in &points_2d {
+                        hits[(cell(v, rows) * columns + cell(u, columns)) as usize] += 1;
+                    }
+                    assert!(
+                        hits.iter().all(|&hits| hits == 1),
+                        "2D, {}x{} cells",
+                        columns,
+                        rows
+                    );
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,215 @@
+use cgmath::{InnerSpace, Quaternion, Rad, Rotation3};
+use serde::{Deserialize, Serialize};
+
+use super::{aabb::AABB, math::degrees_to_radians, types::*};
+
+/// Uniform scale, then a rotation of `angle` degrees around `axis`, then a
+/// translation
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub axis: Vec3,
+    pub angle: Float,
+    pub scale: Float,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::new(0.0, 0.0, 0.0),
+            axis: Vec3::new(0.0, 1.0, 0.0),
+            angle: 0.0,
+            scale: 1.0,
+        }
+    }
+}
+
+impl Transform {
+    fn similarity(&self) -> Similarity {
+        let rotation = if self.axis.magnitude2() > 0.0 {
+            Quaternion::from_axis_angle(self.axis.normalize(), Rad(degrees_to_radians(self.angle)))
+        } else {
+            Quaternion::new(1.0, 0.0, 0.0, 0.0)
+        };
+
+        Similarity {
+            translation: self.translation,
+            rotation,
+            scale: self.scale,
+        }
+    }
+}
+
+/// Transform in the form used for applying and interpolating it
+#[derive(Debug, Clone, Copy)]
+pub struct Similarity {
+    pub translation: Vec3,
+    /// Unit quaternion
+    pub rotation: Quaternion<Float>,
+    pub scale: Float,
+}
+
+impl Similarity {
+    pub fn point(&self, point: Vec3) -> Vec3 {
+        self.rotation * (point * self.scale) + self.translation
+    }
+
+    pub fn vector(&self, vector: Vec3) -> Vec3 {
+        self.rotation * (vector * self.scale)
+    }
+
+    /// Rotates a normal, uniform scaling doesn't change its direction
+    pub fn normal(&self, normal: Vec3) -> Vec3 {
+        self.rotation * normal
+    }
+
+    pub fn inverse_point(&self, point: Vec3) -> Vec3 {
+        self.inverse_vector(point - self.translation)
+    }
+
+    pub fn inverse_vector(&self, vector: Vec3) -> Vec3 {
+        (self.rotation.conjugate() * vector) / self.scale
+    }
+
+    pub fn inverse_normal(&self, normal: Vec3) -> Vec3 {
+        self.rotation.conjugate() * normal
+    }
+
+    /// Blends linearly towards `other`, taking the shortest rotation
+    pub fn interpolate(&self, other: &Similarity, amount: Float) -> Similarity {
+        Similarity {
+            translation: self.translation + (other.translation - self.translation) * amount,
+            rotation: slerp(self.rotation, other.rotation, amount),
+            scale: self.scale + (other.scale - self.scale) * amount,
+        }
+    }
+
+    /// Bounding box of a box after transforming it
+    pub fn bounds(&self, bounds: AABB) -> AABB {
+        corners(bounds)
+            .map(|corner| AABB::from_point(self.point(corner)))
+            .into_iter()
+            .reduce(AABB::surround)
+            .unwrap_or_default()
+    }
+}
+
+/// Spherical interpolation along the shorter of the two arcs between the
+/// rotations
+pub fn slerp(from: Quaternion<Float>, to: Quaternion<Float>, amount: Float) -> Quaternion<Float> {
+    let to = if from.dot(to) < 0.0 { -to } else { to };
+    from.slerp(to, amount)
+}
+
+fn corners(bounds: AABB) -> [Vec3; 8] {
+    let (min, max) = (bounds.min, bounds.max);
+    [
+        Vec3::new(min.x, min.y, min.z),
+        Vec3::new(max.x, min.y, min.z),
+        Vec3::new(min.x, max.y, min.z),
+        Vec3::new(max.x, max.y, min.z),
+        Vec3::new(min.x, min.y, max.z),
+        Vec3::new(max.x, min.y, max.z),
+        Vec3::new(min.x, max.y, max.z),
+        Vec3::new(max.x, max.y, max.z),
+    ]
+}
+
+/// Transform at a point in time
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Keyframe {
+    pub time: Float,
+    #[serde(flatten)]
+    pub transform: Transform,
+}
+
+/// How an object is placed over time, times are in the units of the camera
+/// shutter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Motion {
+    Static(Transform),
+    /// Moves from `start` at time 0 to `end` at time 1
+    Linear {
+        start: Transform,
+        end: Transform,
+    },
+    /// Interpolates between keyframes sorted by time, holding the first and
+    /// last transforms outside of them
+    Keyframes(Vec<Keyframe>),
+}
+
+impl Motion {
+    /// Number of steps every segment of the motion is split into when bounding
+    /// it, rotations bulge out between the steps
+    const BOUND_STEPS: usize = 8;
+
+    pub fn at(&self, time: Float) -> Similarity {
+        match self {
+            Motion::Static(transform) => transform.similarity(),
+            Motion::Linear { start, end } => start
+                .similarity()
+                .interpolate(&end.similarity(), time.clamp(0.0, 1.0)),
+            Motion::Keyframes(keyframes) => {
+                let next = keyframes.partition_point(|keyframe| keyframe.time <= time);
+                match (next.checked_sub(1), keyframes.get(next)) {
+                    (Some(previous), Some(next)) => {
+                        let previous = &keyframes[previous];
+                        let amount = (time - previous.time) / (next.time - previous.time);
+                        previous
+                            .transform
+                            .similarity()
+                            .interpolate(&next.transform.similarity(), amount)
+                    }
+                    (Some(last), None) => keyframes[last].transform.similarity(),
+                    (None, Some(first)) => first.transform.similarity(),
+                    (None, None) => Transform::default().similarity(),
+                }
+            }
+        }
+    }
+
+    /// Box containing an object with the local bounds `bounds` over the whole
+    /// motion
+    pub fn bounds(&self, bounds: AABB) -> AABB {
+        let transforms: Vec<Similarity> = match self {
+            Motion::Static(transform) => return transform.similarity().bounds(bounds),
+            Motion::Linear { start, end } => vec![start.similarity(), end.similarity()],
+            Motion::Keyframes(keyframes) => keyframes
+                .iter()
+                .map(|keyframe| keyframe.transform.similarity())
+                .collect(),
+        };
+        if transforms.len() < 2 {
+            return self.at(0.0).bounds(bounds);
+        }
+
+        // Farthest any point of the box gets from the origin it rotates around
+        let radius = corners(bounds)
+            .iter()
+            .map(|corner| corner.magnitude())
+            .fold(0.0, Float::max);
+
+        let mut result = transforms[0].bounds(bounds);
+        for pair in transforms.windows(2) {
+            let scale = pair[0].scale.abs().max(pair[1].scale.abs());
+            let angle = 2.0 * pair[0].rotation.dot(pair[1].rotation).abs().min(1.0).acos();
+
+            // Points move on an arc between the steps, which leaves the chord
+            // by at most its sagitta
+            let step = angle / Self::BOUND_STEPS as Float;
+            let bulge = radius * scale * (1.0 - (step / 2.0).cos());
+            let bulge = Vec3::new(bulge, bulge, bulge);
+
+            for step in 1..=Self::BOUND_STEPS {
+                let amount = step as Float / Self::BOUND_STEPS as Float;
+                let mut step_bounds = pair[0].interpolate(&pair[1], amount).bounds(bounds);
+                step_bounds.min -= bulge;
+                step_bounds.max += bulge;
+                result = AABB::surround(result, step_bounds);
+            }
+        }
+
+        result
+    }
+}
@@ -6,14 +6,13 @@ use crate::{
     utils::{
         aabb::{Bounded, AABB},
         color::Color,
-        ray::{HitRecord, Ray},
-        sample::sample_unit_sphere_volume,
-        types::{Float, Vec3},
+        ray::{HitRecord, Ray, ScatterSample},
+        sample::{sample_unit_sphere_surface, Sampler},
+        types::{Float, Vec3, PI},
     },
 };
 use cgmath::InnerSpace;
 use derive_new::new;
-use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -32,9 +31,19 @@ impl Volume {
 }
 
 impl Hittable for Volume {
-    fn hit(&self, ray: &Ray, tmin: Float, tmax: Float) -> Option<HitRecord<MaterialHandle>> {
-        let mut hit1 = self.boundary.hit(ray, -f32::INFINITY, f32::INFINITY)?;
-        let mut hit2 = self.boundary.hit(ray, hit1.t + tmin, f32::INFINITY)?;
+    fn hit(
+        &self,
+        ray: &Ray,
+        tmin: Float,
+        tmax: Float,
+        sampler: &mut dyn Sampler,
+    ) -> Option<HitRecord<MaterialHandle>> {
+        let mut hit1 = self
+            .boundary
+            .hit(ray, -f32::INFINITY, f32::INFINITY, sampler)?;
+        let mut hit2 = self
+            .boundary
+            .hit(ray, hit1.t + tmin, f32::INFINITY, sampler)?;
 
         if hit1.t < tmin {
             hit1.t = tmin;
@@ -54,7 +63,7 @@ impl Hittable for Volume {
 
         let ray_length = ray.direction.magnitude();
         let distance_inside_boundary = (hit2.t - hit1.t) * ray_length;
-        let random_float: Float = thread_rng().gen_range(0.0..=1.0);
+        let random_float: Float = 1.0 - sampler.next_1d();
         let hit_distance = self.neg_inv_density * random_float.ln();
 
         if hit_distance > distance_inside_boundary {
@@ -79,11 +88,30 @@ pub struct Isotropic {
 
 #[typetag::serde]
 impl Material for Isotropic {
-    fn scatter(&self, _: &Ray, hit: &HitRecord<MaterialHandle>) -> (Color, Option<Ray>) {
-        (
-            self.color,
-            Some(Ray::new(hit.point, sample_unit_sphere_volume())),
-        )
+    fn albedo(&self, _hit: &HitRecord<MaterialHandle>) -> Color {
+        self.color
+    }
+
+    fn eval(&self, _ray: &Ray, _hit: &HitRecord<MaterialHandle>, _direction: Vec3) -> Color {
+        self.color * (1.0 / (4.0 * PI))
+    }
+
+    fn sample(
+        &self,
+        _ray: &Ray,
+        _hit: &HitRecord<MaterialHandle>,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterSample> {
+        Some(ScatterSample {
+            direction: sample_unit_sphere_surface(sampler.next_2d()),
+            weight: self.color,
+            pdf: 1.0 / (4.0 * PI),
+            specular: false,
+        })
+    }
+
+    fn pdf(&self, _ray: &Ray, _hit: &HitRecord<MaterialHandle>, _direction: Vec3) -> Float {
+        1.0 / (4.0 * PI)
     }
 }
 
@@ -1,19 +1,25 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 use egui::{ClippedMesh, ComboBox, Context, TexturesDelta};
 use egui_wgpu_backend::{BackendError, RenderPass, ScreenDescriptor};
 use pixels::{wgpu, PixelsContext};
 use ray_tracing_rust::backgrounds::{GradientBackground, SkyMap, UniformBackground};
+use ray_tracing_rust::core::aov::Aov;
+use ray_tracing_rust::core::bvh::SplitMethod;
+use ray_tracing_rust::core::denoise::Denoiser;
 use ray_tracing_rust::core::mesh::Mesh;
-use ray_tracing_rust::core::render::{render, RenderTarget};
-use ray_tracing_rust::core::scene::RenderMode;
+use ray_tracing_rust::core::render::{RenderJob, RenderTarget, TargetView};
 use ray_tracing_rust::core::scene::Scene;
+use ray_tracing_rust::core::scene::{Integrator, RenderMode};
+use ray_tracing_rust::core::tiles::TileOrder;
+use ray_tracing_rust::core::tonemap::ToneMapper;
+use ray_tracing_rust::core::traits::Background;
 use ray_tracing_rust::gui::gui::Editable;
 use ray_tracing_rust::materials::{Dielectric, Emission, Lambertian, Metal, MixMaterial};
 use ray_tracing_rust::objects::Sphere;
 use ray_tracing_rust::utils::color::Color;
+use ray_tracing_rust::utils::sample::SamplerKind;
 use ray_tracing_rust::utils::types::*;
 use ray_tracing_rust::volume::*;
 use winit::window::Window;
@@ -39,7 +45,7 @@ impl Framework {
         height: u32,
         scale_factor: f32,
         pixels: &pixels::Pixels,
-        target: Rc<RefCell<RenderTarget>>,
+        target: Arc<Mutex<RenderTarget>>,
     ) -> Self {
         let max_texture_size = pixels.device().limits().max_texture_dimension_2d as usize;
 
@@ -118,11 +124,15 @@ impl Framework {
             scene
         }
 
+        let scene = setup_scene();
         let gui = Gui {
             render_target: target,
             continuous_mode: false,
+            max_samples: 1000,
             last_time: Duration::new(0, 0),
-            scene: setup_scene(),
+            background: scene.background.box_clone(),
+            scene: Arc::new(RwLock::new(scene)),
+            job: None,
         };
 
         Self {
@@ -203,10 +213,16 @@ impl Framework {
 
 /// Application state
 struct Gui {
-    render_target: Rc<RefCell<RenderTarget>>,
-    scene: Scene,
+    render_target: Arc<Mutex<RenderTarget>>,
+    scene: Arc<RwLock<Scene>>,
+    /// Render running in the background, if any
+    job: Option<RenderJob>,
     last_time: Duration,
     continuous_mode: bool,
+    /// Continuous mode stops adding passes once this many samples were accumulated
+    max_samples: u32,
+    /// Copy of the scene background edited by the widgets
+    background: Box<dyn Background>,
 }
 
 impl Gui {
@@ -215,62 +231,96 @@ impl Gui {
         egui::Window::new("Settings")
             .open(&mut true)
             .show(ctx, |ui| {
-                let scene = &mut self.scene;
+                // The widgets edit copies, the scene is only locked for writing
+                // when something changed, which waits for the tiles in flight
+                let (original, mut settings, mut camera, active_camera, camera_names) = {
+                    let scene = self.scene.read().unwrap();
+                    (
+                        scene.settings,
+                        scene.settings,
+                        scene.camera().clone(),
+                        scene.active_camera.clone(),
+                        scene.cameras.keys().cloned().collect::<Vec<String>>(),
+                    )
+                };
+                let mut active = active_camera.clone();
+                let mut background_modified = false;
                 let mut modified = false;
 
-                ui.label("Samples per pixel:");
+                ui.label("Samples per pass:");
                 ui.add(egui::Slider::new(
-                    &mut scene.settings.samples_per_pixel,
+                    &mut settings.samples_per_pixel,
                     1..=10000,
                 ));
+                modified |= ui
+                    .add(egui::Checkbox::new(
+                        &mut settings.adaptive_sampling,
+                        "Adaptive sampling",
+                    ))
+                    .changed();
+                if settings.adaptive_sampling {
+                    ui.label("Min samples:");
+                    modified |= ui
+                        .add(
+                            egui::Slider::new(&mut settings.min_samples, 2..=1024)
+                                .logarithmic(true),
+                        )
+                        .changed();
+                    ui.label("Max samples per pixel:");
+                    modified |= ui
+                        .add(
+                            egui::Slider::new(&mut settings.max_samples, 2..=100000)
+                                .logarithmic(true),
+                        )
+                        .changed();
+                    ui.label("Noise threshold:");
+                    modified |= ui
+                        .add(
+                            egui::Slider::new(&mut settings.noise_threshold, 0.0001..=0.1)
+                                .logarithmic(true),
+                        )
+                        .changed();
+                }
                 ui.label("Max ray depth:");
-                ui.add(egui::Slider::new(&mut scene.settings.max_ray_depth, 1..=50));
+                modified |= ui
+                    .add(egui::Slider::new(&mut settings.max_ray_depth, 1..=500).logarithmic(true))
+                    .changed();
+                ui.label("Russian roulette depth:");
+                modified |= ui
+                    .add(egui::Slider::new(&mut settings.roulette_depth, 1..=50))
+                    .changed();
                 ui.horizontal(|ui| {
                     ui.label("Clamp:");
-                    ui.add(egui::DragValue::new(&mut scene.settings.clamp_indirect).speed(0.5));
+                    modified |= ui
+                        .add(egui::DragValue::new(&mut settings.clamp_indirect).speed(0.5))
+                        .changed();
                 });
 
                 ui.horizontal(|ui| {
                     ui.label("Render mode:");
                     ComboBox::from_label("")
-                        .selected_text(format!("{:?}", scene.settings.mode))
+                        .selected_text(format!("{:?}", settings.mode))
                         .show_ui(ui, |ui| {
                             if ui
-                                .selectable_value(
-                                    &mut scene.settings.mode,
-                                    RenderMode::Full,
-                                    "Full",
-                                )
+                                .selectable_value(&mut settings.mode, RenderMode::Full, "Full")
                                 .clicked()
                             {
                                 modified = true
                             };
                             if ui
-                                .selectable_value(
-                                    &mut scene.settings.mode,
-                                    RenderMode::Clay,
-                                    "Clay",
-                                )
+                                .selectable_value(&mut settings.mode, RenderMode::Clay, "Clay")
                                 .clicked()
                             {
                                 modified = true
                             };
                             if ui
-                                .selectable_value(
-                                    &mut scene.settings.mode,
-                                    RenderMode::Normal,
-                                    "Normal",
-                                )
+                                .selectable_value(&mut settings.mode, RenderMode::Normal, "Normal")
                                 .clicked()
                             {
                                 modified = true
                             };
                             if ui
-                                .selectable_value(
-                                    &mut scene.settings.mode,
-                                    RenderMode::Random,
-                                    "Random",
-                                )
+                                .selectable_value(&mut settings.mode, RenderMode::Random, "Random")
                                 .clicked()
                             {
                                 modified = true
@@ -278,63 +328,290 @@ impl Gui {
                         });
                 });
 
+                ui.horizontal(|ui| {
+                    ui.label("Integrator:");
+                    ComboBox::from_id_source("integrator")
+                        .selected_text(format!("{:?}", settings.integrator))
+                        .show_ui(ui, |ui| {
+                            modified |= ui
+                                .selectable_value(
+                                    &mut settings.integrator,
+                                    Integrator::Path,
+                                    "Path",
+                                )
+                                .clicked();
+                            modified |= ui
+                                .selectable_value(&mut settings.integrator, Integrator::Mis, "Mis")
+                                .clicked();
+                        });
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label("Sampler:");
+                    ComboBox::from_id_source("sampler")
+                        .selected_text(format!("{:?}", settings.sampler))
+                        .show_ui(ui, |ui| {
+                            for (sampler, name) in [
+                                (SamplerKind::Independent, "Independent"),
+                                (SamplerKind::Stratified, "Stratified"),
+                                (SamplerKind::Halton, "Halton"),
+                                (SamplerKind::Sobol, "Sobol"),
+                            ] {
+                                modified |= ui
+                                    .selectable_value(&mut settings.sampler, sampler, name)
+                                    .clicked();
+                            }
+                        });
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label("Seed:");
+                    modified |= ui.add(egui::DragValue::new(&mut settings.seed)).changed();
+                });
+
+                ui.label("Tile size:");
+                ui.add(egui::Slider::new(&mut settings.tile_size, 4..=256));
+                ui.horizontal(|ui| {
+                    ui.label("Tile order:");
+                    ComboBox::from_id_source("tile_order")
+                        .selected_text(format!("{:?}", settings.tile_order))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(
+                                &mut settings.tile_order,
+                                TileOrder::Scanline,
+                                "Scanline",
+                            );
+                            ui.selectable_value(
+                                &mut settings.tile_order,
+                                TileOrder::Spiral,
+                                "Spiral",
+                            );
+                            ui.selectable_value(
+                                &mut settings.tile_order,
+                                TileOrder::Hilbert,
+                                "Hilbert",
+                            );
+                        });
+                });
+
+                modified |= ui
+                    .add(egui::Checkbox::new(&mut settings.aovs, "Render AOVs"))
+                    .changed();
+
+                // The denoiser is guided by the albedo and normal AOVs
+                let mut denoise = self.render_target.lock().unwrap().denoiser.is_some();
+                if ui
+                    .add(egui::Checkbox::new(&mut denoise, "Denoise"))
+                    .changed()
+                {
+                    if denoise && !settings.aovs {
+                        settings.aovs = true;
+                        modified = true;
+                    }
+                    self.render_target
+                        .lock()
+                        .unwrap()
+                        .set_denoiser(denoise.then(Denoiser::default));
+                }
                 ui.add(egui::Checkbox::new(
-                    &mut scene.settings.enable_multithreading,
+                    &mut settings.enable_multithreading,
                     "Enable multithreading",
                 ));
                 ui.add(egui::Checkbox::new(
-                    &mut scene.settings.enable_bvh_tree,
+                    &mut settings.enable_bvh_tree,
                     "Enable Bvh tree",
                 ));
+                ui.horizontal(|ui| {
+                    ui.label("Bvh split:");
+                    ComboBox::from_id_source("bvh_split")
+                        .selected_text(format!("{:?}", settings.bvh.split))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(
+                                &mut settings.bvh.split,
+                                SplitMethod::Median,
+                                "Median",
+                            );
+                            ui.selectable_value(&mut settings.bvh.split, SplitMethod::Sah, "Sah");
+                        });
+                });
+                if settings.bvh.split == SplitMethod::Sah {
+                    ui.label("Bvh bins:");
+                    ui.add(egui::Slider::new(&mut settings.bvh.bins, 2..=64));
+                }
                 ui.add(egui::Checkbox::new(
                     &mut self.continuous_mode,
                     "Continuous mode",
                 ));
+                ui.label("Max samples:");
+                ui.add(egui::Slider::new(&mut self.max_samples, 1..=100000).logarithmic(true));
 
                 ui.separator();
                 ui.heading("Scene Settings");
                 ui.collapsing("Camera", |ui| {
-                    scene.camera.display_ui(ui, &mut modified);
+                    if !camera_names.is_empty() {
+                        ui.horizontal(|ui| {
+                            ui.label("Active camera:");
+                            ComboBox::from_id_source("active_camera")
+                                .selected_text(active.as_deref().unwrap_or("Default"))
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(&mut active, None, "Default");
+                                    for name in &camera_names {
+                                        ui.selectable_value(
+                                            &mut active,
+                                            Some(name.clone()),
+                                            name.as_str(),
+                                        );
+                                    }
+                                });
+                        });
+                    }
+                    camera.display_ui(ui, &mut modified);
                 });
 
                 ui.collapsing("Background", |ui| {
-                    scene.background.display_ui(ui, &mut modified);
+                    self.background.display_ui(ui, &mut background_modified);
                     ui.horizontal(|ui| {
                         ui.menu_button("Change background", |ui| {
                             if ui.button("Uniform background").clicked() {
-                                scene.background =
+                                self.background =
                                     Box::new(UniformBackground::new(Color::new(0.8, 0.8, 0.8)));
                                 ui.close_menu();
-                                modified = true;
+                                background_modified = true;
                             }
                             if ui.button("Gradient background").clicked() {
-                                scene.background = Box::new(GradientBackground::new(
+                                self.background = Box::new(GradientBackground::new(
                                     Color::new(0.5, 0.7, 1.0),
                                     Color::new(1.0, 1.0, 1.0),
                                 ));
                                 ui.close_menu();
-                                modified = true;
+                                background_modified = true;
                             }
                             if ui.button("Sky map").clicked() {}
                         });
                     })
                 });
 
+                let switched_camera = active != active_camera;
+                modified |= background_modified || switched_camera;
+
+                // Make sure the job doesn't pick up the modified scene before it is restarted
+                if modified {
+                    if let Some(job) = &self.job {
+                        job.cancel();
+                    }
+                }
+                if modified || settings != original {
+                    let mut scene = self.scene.write().unwrap();
+                    scene.settings = settings;
+                    if switched_camera {
+                        // The edits were made to the camera that was active before
+                        scene.active_camera = active;
+                    } else if modified {
+                        *scene.camera_mut() = camera;
+                    }
+                    if background_modified {
+                        scene.background = self.background.box_clone();
+                    }
+                }
+                let samples_per_pass = settings.samples_per_pixel;
+
                 ui.separator();
-                if ui.button("Render Image").clicked() {
-                    let now = Instant::now();
-                    render(&mut *self.render_target.borrow_mut(), &self.scene);
-                    self.last_time = Instant::now().duration_since(now);
+                ui.horizontal(|ui| {
+                    let mut view = self.render_target.lock().unwrap().view;
+                    ui.label("View:");
+                    ComboBox::from_id_source("view")
+                        .selected_text(format!("{:?}", view))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut view, TargetView::Image, "Image");
+                            ui.selectable_value(&mut view, TargetView::SampleCount, "SampleCount");
+                            for aov in Aov::ALL {
+                                ui.selectable_value(
+                                    &mut view,
+                                    TargetView::Aov(aov),
+                                    format!("{:?}", aov),
+                                );
+                            }
+                        });
+
+                    let mut target = self.render_target.lock().unwrap();
+                    if target.view != view {
+                        target.set_view(view);
+                    }
+                });
+
+                // Tone mapping only redraws the accumulated samples
+                ui.collapsing("Tone mapping", |ui| {
+                    let mut tone_mapping = self.render_target.lock().unwrap().tone_mapping;
+                    ui.label("Exposure:");
+                    ui.add(
+                        egui::Slider::new(&mut tone_mapping.exposure, -10.0..=10.0).text("stops"),
+                    );
+                    ui.horizontal(|ui| {
+                        ui.label("Curve:");
+                        ComboBox::from_id_source("tone_mapper")
+                            .selected_text(format!("{:?}", tone_mapping.mapper))
+                            .show_ui(ui, |ui| {
+                                for (mapper, name) in [
+                                    (ToneMapper::Clamp, "Clamp"),
+                                    (ToneMapper::Reinhard, "Reinhard"),
+                                    (ToneMapper::Filmic, "Filmic"),
+                                    (ToneMapper::AgX, "AgX"),
+                                ] {
+                                    ui.selectable_value(&mut tone_mapping.mapper, mapper, name);
+                                }
+                            });
+                    });
+
+                    let mut target = self.render_target.lock().unwrap();
+                    if target.tone_mapping != tone_mapping {
+                        target.set_tone_mapping(tone_mapping);
+                    }
+                });
+
+                let render_clicked = ui.button("Render Image").clicked();
+
+                // Start accumulating from scratch whenever the scene changes
+                if modified || render_clicked {
+                    self.stop_job();
+                    self.render_target.lock().unwrap().clear();
                 }
 
-                ui.label(format!("Last render took: {:?}", self.last_time));
-                ui.label(format!("Using {:?} threads", rayon::current_num_threads()));
+                let (samples, converged) = {
+                    let target = self.render_target.lock().unwrap();
+                    (target.samples, target.converged)
+                };
+                let idle = self.job.as_ref().is_none_or(|job| job.is_finished());
+
+                if render_clicked {
+                    self.start_job(samples_per_pass);
+                } else if self.continuous_mode && idle && !converged && samples < self.max_samples {
+                    self.start_job(self.max_samples);
+                }
 
-                if modified && self.continuous_mode {
-                    let now = Instant::now();
-                    render(&mut *self.render_target.borrow_mut(), &self.scene);
-                    self.last_time = Instant::now().duration_since(now);
+                if let Some(job) = &self.job {
+                    if !job.is_finished() {
+                        self.last_time = job.elapsed();
+                    }
                 }
+
+                ui.label(format!("Render time: {:?}", self.last_time));
+                ui.label(format!("Accumulated {} samples", samples));
+                ui.label(format!("Using {:?} threads", rayon::current_num_threads()));
             });
     }
+
+    fn start_job(&mut self, total_samples: u32) {
+        self.stop_job();
+        self.job = Some(RenderJob::spawn(
+            Arc::clone(&self.scene),
+            Arc::clone(&self.render_target),
+            total_samples,
+        ));
+    }
+
+    fn stop_job(&mut self) {
+        if let Some(job) = self.job.take() {
+            job.stop();
+        }
+    }
 }
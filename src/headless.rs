@@ -0,0 +1,669 @@
+#![deny(clippy::all)]
+#![forbid(unsafe_code)]
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use clap::{ArgEnum, Parser};
+use ray_tracing_rust::core::camera::{Stereo, StereoLayout};
+use ray_tracing_rust::core::denoise::Denoiser;
+use ray_tracing_rust::core::output::{self, OutputFormat};
+use ray_tracing_rust::core::render::{
+    render_progressive, render_sequence, RenderTarget, TargetView,
+};
+use ray_tracing_rust::core::scene::{Integrator, RenderMode, Scene};
+use ray_tracing_rust::core::tiles::{Region, Tile, TileOrder, TileReport};
+use ray_tracing_rust::core::tonemap::{ToneMapper, ToneMapping};
+use ray_tracing_rust::utils::sample::SamplerKind;
+use ray_tracing_rust::utils::types::Float;
+
+const DEFAULT_WIDTH: usize = 1000;
+
+/// Render a scene without opening a window
+#[derive(Parser)]
+#[clap(name = "headless", version)]
+struct Args {
+    /// Scene json file to render, or a linear exr, hdr or pfm image to only
+    /// tone map again
+    scene: String,
+
+    /// Where to write the rendered image, the format is picked from the
+    /// extension (png, jpg, exr, hdr or pfm)
+    #[clap(short, long, default_value = "render.png")]
+    output: String,
+
+    /// Override the total number of samples per pixel, the average over all
+    /// pixels with adaptive sampling
+    #[clap(short, long)]
+    samples: Option<u32>,
+
+    /// Stop sampling pixels once their noise falls below the threshold and
+    /// give their samples to the noisy pixels, up to the maximum sample count
+    #[clap(long)]
+    adaptive: bool,
+
+    /// Override the samples every pixel gets before adaptive sampling may stop it
+    #[clap(long)]
+    min_samples: Option<u32>,
+
+    /// Override the maximum number of samples adaptive sampling takes per pixel
+    #[clap(long)]
+    max_samples: Option<u32>,
+
+    /// Override the noise threshold of adaptive sampling
+    #[clap(long)]
+    noise_threshold: Option<Float>,
+
+    /// Also render the AOVs and write them as extra layers, needs exr output
+    #[clap(long)]
+    aovs: bool,
+
+    /// Denoise the image after rendering, guided by the albedo and normal AOVs
+    #[clap(long)]
+    denoise: bool,
+
+    /// Override the radius of the denoising filter in pixels
+    #[clap(long)]
+    denoise_radius: Option<usize>,
+
+    /// Also write a heat map of the number of samples taken for every pixel
+    /// (png or jpg)
+    #[clap(long)]
+    sample_map: Option<String>,
+
+    /// Number of samples added by each progressive pass, defaults to
+    /// rendering everything in a single pass, or to the minimum sample count
+    /// with adaptive sampling so that samples move to noisy pixels early
+    #[clap(long)]
+    pass_samples: Option<u32>,
+
+    /// Stop after the first pass that ends past this many seconds, per frame
+    /// when rendering a sequence
+    #[clap(long, parse(try_from_str = parse_time_limit))]
+    time_limit: Option<Duration>,
+
+    /// Render the frames START-END of the camera animation as an image
+    /// sequence. Frame numbers replace a run of '#' in the output paths, or
+    /// are appended to the file names
+    #[clap(long, parse(try_from_str = parse_frames))]
+    frames: Option<RangeInclusive<u32>>,
+
+    /// Override the maximum ray depth
+    #[clap(short, long)]
+    depth: Option<u32>,
+
+    /// Override the depth after which paths are randomly terminated
+    #[clap(long)]
+    roulette_depth: Option<u32>,
+
+    /// Override the render mode
+    #[clap(short, long, arg_enum)]
+    mode: Option<Mode>,
+
+    /// Image width in pixels
+    #[clap(long)]
+    width: Option<usize>,
+
+    /// Image height in pixels, derived from the camera aspect ratio if omitted
+    #[clap(long)]
+    height: Option<usize>,
+
+    /// Render from the camera with this name instead of the active one
+    #[clap(long)]
+    camera: Option<String>,
+
+    /// Render the views of both eyes into one image, each eye gets half of it
+    #[clap(long, arg_enum)]
+    stereo: Option<Layout>,
+
+    /// Distance between the eyes of a stereo render, in scene units
+    #[clap(long, default_value_t = 0.065)]
+    interaxial: Float,
+
+    /// Distance at which the eyes of a stereo render converge, defaults to the
+    /// focus distance of the camera
+    #[clap(long)]
+    convergence: Option<Float>,
+
+    /// Override the integrator
+    #[clap(short, long, arg_enum)]
+    integrator: Option<IntegratorArg>,
+
+    /// Override the random seed, renders with the same seed are identical
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Exposure adjustment in stops, applied before tone mapping
+    #[clap(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    exposure: Float,
+
+    /// Tone mapping curve for png and jpg output, exr, hdr and pfm output is
+    /// always linear
+    #[clap(long, arg_enum, default_value = "clamp")]
+    tone_map: Mapper,
+
+    /// Override the sampler generating the random numbers of every sample
+    #[clap(long, arg_enum)]
+    sampler: Option<SamplerArg>,
+
+    /// Override the side length of the render tiles in pixels
+    #[clap(long)]
+    tile_size: Option<usize>,
+
+    /// Override the order in which tiles are rendered
+    #[clap(long, arg_enum)]
+    tile_order: Option<Order>,
+
+    /// Only render the region of interest given as x,y,width,height in pixels,
+    /// the rest of the image is left black
+    #[clap(long, parse(try_from_str = parse_region))]
+    region: Option<Region>,
+
+    /// Print how long the slowest tiles took to render
+    #[clap(long)]
+    tile_stats: bool,
+
+    /// Render on a single thread
+    #[clap(long)]
+    single_threaded: bool,
+
+    /// Disable the Bvh tree acceleration structure
+    #[clap(long)]
+    no_bvh: bool,
+
+    /// Don't print progress to stderr
+    #[clap(short, long)]
+    quiet: bool,
+}
+
+#[derive(Clone, Copy, ArgEnum)]
+enum Mode {
+    Full,
+    Clay,
+    Random,
+    Normal,
+}
+
+impl From<Mode> for RenderMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Full => RenderMode::Full,
+            Mode::Clay => RenderMode::Clay,
+            Mode::Random => RenderMode::Random,
+            Mode::Normal => RenderMode::Normal,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ArgEnum)]
+enum IntegratorArg {
+    Path,
+    Mis,
+}
+
+impl From<IntegratorArg> for Integrator {
+    fn from(integrator: IntegratorArg) -> Self {
+        match integrator {
+            IntegratorArg::Path => Integrator::Path,
+            IntegratorArg::Mis => Integrator::Mis,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ArgEnum)]
+enum SamplerArg {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl From<SamplerArg> for SamplerKind {
+    fn from(sampler: SamplerArg) -> Self {
+        match sampler {
+            SamplerArg::Independent => SamplerKind::Independent,
+            SamplerArg::Stratified => SamplerKind::Stratified,
+            SamplerArg::Halton => SamplerKind::Halton,
+            SamplerArg::Sobol => SamplerKind::Sobol,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ArgEnum)]
+enum Mapper {
+    Clamp,
+    Reinhard,
+    Filmic,
+    Agx,
+}
+
+impl From<Mapper> for ToneMapper {
+    fn from(mapper: Mapper) -> Self {
+        match mapper {
+            Mapper::Clamp => ToneMapper::Clamp,
+            Mapper::Reinhard => ToneMapper::Reinhard,
+            Mapper::Filmic => ToneMapper::Filmic,
+            Mapper::Agx => ToneMapper::AgX,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ArgEnum)]
+enum Order {
+    Scanline,
+    Spiral,
+    Hilbert,
+}
+
+impl From<Order> for TileOrder {
+    fn from(order: Order) -> Self {
+        match order {
+            Order::Scanline => TileOrder::Scanline,
+            Order::Spiral => TileOrder::Spiral,
+            Order::Hilbert => TileOrder::Hilbert,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ArgEnum)]
+enum Layout {
+    SideBySide,
+    TopBottom,
+}
+
+impl From<Layout> for StereoLayout {
+    fn from(layout: Layout) -> Self {
+        match layout {
+            Layout::SideBySide => StereoLayout::SideBySide,
+            Layout::TopBottom => StereoLayout::TopBottom,
+        }
+    }
+}
+
+fn parse_region(region: &str) -> Result<Region, String> {
+    let values = region
+        .split(',')
+        .map(|value| value.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    match values[..] {
+        [x, y, width, height] => Ok(Region::new(x, y, width, height)),
+        _ => Err("expected x,y,width,height".to_owned()),
+    }
+}
+
+fn parse_frames(frames: &str) -> Result<RangeInclusive<u32>, String> {
+    let (start, end) = frames.split_once('-').unwrap_or((frames, frames));
+    let start = start.trim().parse::<u32>().map_err(|e| e.to_string())?;
+    let end = end.trim().parse::<u32>().map_err(|e| e.to_string())?;
+
+    if start > end {
+        return Err("the first frame comes after the last one".to_owned());
+    }
+    Ok(start..=end)
+}
+
+fn parse_time_limit(seconds: &str) -> Result<Duration, String> {
+    let seconds = seconds.trim().parse::<f64>().map_err(|e| e.to_string())?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| "expected a finite, non-negative number of seconds".to_owned())
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+    let args = Args::parse();
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let tone_mapping = ToneMapping {
+        exposure: args.exposure,
+        mapper: args.tone_map.into(),
+    };
+
+    // Images were already rendered, only tone map them again
+    if OutputFormat::from_path(&args.scene).is_some() {
+        let mut target = output::load(&args.scene)
+            .map_err(|e| format!("could not load '{}': {}", args.scene, e))?;
+        target.set_tone_mapping(tone_mapping);
+        return output::save(&target, &args.output)
+            .map_err(|e| format!("could not write '{}': {}", args.output, e));
+    }
+
+    let mut scene =
+        Scene::load(&args.scene).map_err(|e| format!("could not load '{}': {}", args.scene, e))?;
+
+    // Apply overrides
+    if args.adaptive {
+        scene.settings.adaptive_sampling = true;
+    }
+    if let Some(samples) = args.min_samples {
+        scene.settings.min_samples = samples;
+    }
+    if let Some(samples) = args.max_samples {
+        scene.settings.max_samples = samples;
+    }
+    if let Some(threshold) = args.noise_threshold {
+        scene.settings.noise_threshold = threshold;
+    }
+
+    let total_samples = args.samples.unwrap_or(scene.settings.samples_per_pixel);
+    if total_samples == 0 {
+        return Err("the number of samples per pixel has to be at least 1".to_owned());
+    }
+    if scene.settings.adaptive_sampling && scene.settings.max_samples == 0 {
+        return Err("the maximum number of samples per pixel has to be at least 1".to_owned());
+    }
+    // Convergence is only checked between passes
+    let pass_samples = if scene.settings.adaptive_sampling {
+        scene.settings.min_samples.clamp(1, total_samples)
+    } else {
+        total_samples
+    };
+    scene.settings.samples_per_pixel = args.pass_samples.unwrap_or(pass_samples);
+    if let Some(depth) = args.depth {
+        scene.settings.max_ray_depth = depth;
+    }
+    if let Some(depth) = args.roulette_depth {
+        scene.settings.roulette_depth = depth;
+    }
+    if let Some(mode) = args.mode {
+        scene.settings.mode = mode.into();
+    }
+    if let Some(integrator) = args.integrator {
+        scene.settings.integrator = integrator.into();
+    }
+    if let Some(seed) = args.seed {
+        scene.settings.seed = seed;
+    }
+    if let Some(sampler) = args.sampler {
+        scene.settings.sampler = sampler.into();
+    }
+    if let Some(tile_size) = args.tile_size {
+        scene.settings.tile_size = tile_size;
+    }
+    if let Some(order) = args.tile_order {
+        scene.settings.tile_order = order.into();
+    }
+    if args.region.is_some() {
+        scene.settings.region = args.region;
+    }
+    if args.aovs {
+        scene.settings.aovs = true;
+    }
+    if args.single_threaded {
+        scene.settings.enable_multithreading = false;
+    }
+    if args.no_bvh {
+        scene.settings.enable_bvh_tree = false;
+    }
+
+    if OutputFormat::from_path(&args.output).is_none() {
+        return Err(format!("unknown image format for '{}'", args.output));
+    }
+    if OutputFormat::from_path(&args.output) != Some(OutputFormat::Exr) {
+        if args.aovs {
+            return Err("AOVs can only be written to exr files".to_owned());
+        }
+        // Scenes saved with AOVs on still render to the other formats, which
+        // have no room for them
+        scene.settings.aovs = false;
+    }
+    if let Some(path) = &args.sample_map {
+        if OutputFormat::from_path(path).is_none_or(|format| format.is_hdr()) {
+            return Err(format!("sample map '{}' has to be a png or jpg", path));
+        }
+    }
+    // The denoiser needs the guides, even when they aren't written
+    let write_aovs = scene.settings.aovs;
+    if args.denoise {
+        scene.settings.aovs = true;
+    }
+
+    if let Some(name) = &args.camera {
+        if !scene.cameras.contains_key(name) {
+            return Err(format!("the scene has no camera named '{}'", name));
+        }
+        scene.active_camera = Some(name.clone());
+    }
+    if let Some(layout) = args.stereo {
+        scene.camera_mut().stereo = Some(Stereo {
+            interaxial: args.interaxial,
+            convergence: args.convergence,
+            layout: layout.into(),
+        });
+    }
+
+    let (width, height) = resolution(args, scene.camera().aspect_ratio);
+    if width < 2 || height < 2 {
+        return Err(format!("invalid resolution {}x{}", width, height));
+    }
+    scene.camera_mut().aspect_ratio = width as Float / height as Float;
+
+    let mut target = RenderTarget::new(width, height);
+    target.tone_mapping = tone_mapping;
+
+    let time_limit = args.time_limit;
+    let tile_times = Mutex::new(Vec::new());
+    let record_tile = |report: &TileReport| {
+        if args.tile_stats {
+            tile_times.lock().unwrap().push(*report);
+        }
+    };
+
+    if let Some(frames) = args.frames.clone() {
+        // Reset after every frame, for the time limit and the statistics
+        let frame_start = Cell::new(Instant::now());
+
+        render_sequence(
+            &mut target,
+            &scene,
+            frames,
+            total_samples,
+            |frame, report, fraction| {
+                record_tile(report);
+                if !args.quiet {
+                    print_progress(&format!("frame {}", frame), fraction);
+                }
+            },
+            |_| time_limit.is_none_or(|limit| frame_start.get().elapsed() < limit),
+            |frame, target| {
+                let result = finish_image(
+                    args,
+                    &scene,
+                    target,
+                    write_aovs,
+                    Some(frame),
+                    frame_start.get(),
+                );
+                frame_start.set(Instant::now());
+                result
+            },
+        )?;
+    } else {
+        let now = Instant::now();
+        render_progressive(
+            &mut target,
+            &scene,
+            total_samples,
+            |report, fraction| {
+                record_tile(report);
+                if !args.quiet {
+                    print_progress("rendering", fraction);
+                }
+            },
+            |_| time_limit.is_none_or(|limit| now.elapsed() < limit),
+        );
+        finish_image(args, &scene, &mut target, write_aovs, None, now)?;
+    }
+
+    if args.tile_stats {
+        print_tile_stats(tile_times.into_inner().unwrap());
+    }
+
+    Ok(())
+}
+
+/// Reports on a finished image and writes it, together with the sample map.
+/// The target is left as it was rendered, for the next frame of a sequence
+fn finish_image(
+    args: &Args,
+    scene: &Scene,
+    target: &mut RenderTarget,
+    write_aovs: bool,
+    frame: Option<u32>,
+    start: Instant,
+) -> Result<(), String> {
+    if !args.quiet {
+        eprintln!();
+        eprintln!(
+            "rendered {}x{} with {} samples in {:?}",
+            target.width,
+            target.height,
+            target.samples,
+            start.elapsed()
+        );
+
+        if scene.settings.adaptive_sampling {
+            let total: u64 = target.pixel_samples.iter().map(|&n| n as u64).sum();
+            eprintln!(
+                "adaptive sampling took {:.1} samples per pixel on average",
+                total as f64 / target.pixel_samples.len() as f64
+            );
+        }
+    }
+
+    let path = |path: &str| match frame {
+        Some(frame) => frame_path(path, frame),
+        None => path.to_owned(),
+    };
+
+    if args.denoise {
+        let mut denoiser = Denoiser::default();
+        if let Some(radius) = args.denoise_radius {
+            denoiser.radius = radius;
+        }
+        target.set_denoiser(Some(denoiser));
+    }
+
+    // AOVs rendered only as guides for the denoiser are left out of the file
+    let guides = (!write_aovs).then(|| std::mem::take(&mut target.aovs));
+    let output = path(&args.output);
+    let saved = output::save(target, &output);
+    if let Some(guides) = guides {
+        target.aovs = guides;
+    }
+    saved.map_err(|e| format!("could not write '{}': {}", output, e))?;
+
+    if let Some(sample_map) = &args.sample_map {
+        let sample_map = path(sample_map);
+        target.set_view(TargetView::SampleCount);
+        output::save(target, &sample_map)
+            .map_err(|e| format!("could not write '{}': {}", sample_map, e))?;
+        target.set_view(TargetView::Image);
+    }
+
+    if args.denoise {
+        target.set_denoiser(None);
+    }
+
+    Ok(())
+}
+
+/// Puts the frame number into a path, in place of the first run of '#' padded
+/// to its length, or else at the end of the file name padded to four digits
+fn frame_path(path: &str, frame: u32) -> String {
+    if let Some(start) = path.find('#') {
+        let length = path[start..].chars().take_while(|&c| c == '#').count();
+        return format!(
+            "{}{:0length$}{}",
+            &path[..start],
+            frame,
+            &path[start + length..],
+            length = length
+        );
+    }
+
+    let path = Path::new(path);
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("");
+    let name = match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => format!("{}_{:04}.{}", stem, frame, extension),
+        None => format!("{}_{:04}", stem, frame),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Work out the output resolution, keeping the camera aspect ratio for any missing dimension
+fn resolution(args: &Args, aspect_ratio: Float) -> (usize, usize) {
+    match (args.width, args.height) {
+        (Some(width), Some(height)) => (width, height),
+        (Some(width), None) => (width, (width as Float / aspect_ratio) as usize),
+        (None, Some(height)) => ((height as Float * aspect_ratio) as usize, height),
+        (None, None) => (
+            DEFAULT_WIDTH,
+            (DEFAULT_WIDTH as Float / aspect_ratio) as usize,
+        ),
+    }
+}
+
+fn print_progress(label: &str, fraction: Float) {
+    const BAR_WIDTH: usize = 40;
+    let filled = ((fraction * BAR_WIDTH as Float) as usize).min(BAR_WIDTH);
+
+    eprint!(
+        "\r{} [{}{}] {:>3}%",
+        label,
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        (fraction * 100.0) as usize
+    );
+    std::io::stderr().flush().ok();
+}
+
+/// Sums up the time spent on every tile over all passes and lists the slowest ones
+fn print_tile_stats(reports: Vec<TileReport>) {
+    const SLOWEST: usize = 10;
+
+    let mut durations: HashMap<Tile, Duration> = HashMap::new();
+    for report in reports {
+        *durations.entry(report.tile).or_default() += report.duration;
+    }
+    let mut tiles: Vec<(Tile, Duration)> = durations.into_iter().collect();
+
+    if tiles.is_empty() {
+        return;
+    }
+
+    let total: Duration = tiles.iter().map(|(_, duration)| *duration).sum();
+    eprintln!(
+        "{} tiles, {:?} in total, {:?} on average",
+        tiles.len(),
+        total,
+        total / tiles.len() as u32
+    );
+
+    tiles.sort_by_key(|(_, duration)| std::cmp::Reverse(*duration));
+    for (tile, duration) in tiles.iter().take(SLOWEST) {
+        eprintln!(
+            "  {:>4},{:<4} {:>3}x{:<3} {:?}",
+            tile.x, tile.y, tile.width, tile.height, duration
+        );
+    }
+}
@@ -5,8 +5,7 @@ use crate::gui::Framework;
 use log::error;
 use pixels::{Error, Pixels, SurfaceTexture};
 use ray_tracing_rust::core::render::RenderTarget;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use winit::dpi::LogicalSize;
 use winit::event::Event;
 use winit::event_loop::{ControlFlow, EventLoop};
@@ -37,7 +36,7 @@ fn main() -> Result<(), Error> {
             .unwrap()
     };
 
-    let target = Rc::new(RefCell::new(RenderTarget::new(
+    let target = Arc::new(Mutex::new(RenderTarget::new(
         RENDER_WIDTH as usize,
         RENDER_HEIGHT as usize,
     )));
@@ -52,7 +51,7 @@ fn main() -> Result<(), Error> {
             window_size.height,
             scale_factor,
             &pixels,
-            Rc::clone(&target),
+            Arc::clone(&target),
         );
 
         (pixels, framework)
@@ -89,12 +88,12 @@ fn main() -> Result<(), Error> {
             }
             // Draw the current frame
             Event::RedrawRequested(_) => {
-                if target.borrow().request_redraw {
-                    pixels
-                        .get_frame()
-                        .copy_from_slice(&target.borrow().data[..]);
-                    target.borrow_mut().request_redraw = false;
+                let mut target = target.lock().unwrap();
+                if target.request_redraw {
+                    pixels.get_frame().copy_from_slice(&target.data[..]);
+                    target.request_redraw = false;
                 }
+                drop(target);
 
                 // Prepare egui
                 framework.prepare(&window);